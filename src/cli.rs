@@ -0,0 +1,124 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use streamline::Config;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "streamline",
+    version,
+    about = "Analyze and optimise media libraries"
+)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(short, long, global = true, default_value = "config.toml")]
+    pub config: PathBuf,
+
+    #[command(flatten)]
+    pub overrides: Overrides,
+
+    /// Ignore cached ffprobe results and probe every file again
+    #[arg(long, global = true)]
+    pub refresh_probe: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// List the media files that would be considered
+    Scan,
+    /// Probe media files and print their streams
+    Probe,
+    /// Show every reason each file needs processing
+    Explain {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ExplainFormat::Text)]
+        format: ExplainFormat,
+
+        /// Include files that already meet their targets
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show the ffmpeg commands that would be run, without running them
+    Plan {
+        /// Write the plan to this file instead of printing it, for use with `apply`
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Run the jobs from a plan file written by `plan --output`
+    Apply {
+        /// Plan file to run
+        plan: PathBuf,
+    },
+    /// Analyze and process media files (default)
+    Process,
+    /// Keep running, processing new and changed files as they arrive
+    Watch,
+    /// Print the effective configuration
+    Config,
+    /// Remove recycled originals past the configured retention limits
+    Purge {
+        /// Empty the recycle directory regardless of the retention limits
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExplainFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+/// Per-run overrides for values from the `[streamline]` config section.
+#[derive(Debug, Args)]
+pub struct Overrides {
+    /// Override the source directory
+    #[arg(long, global = true, value_name = "PATH")]
+    pub source_dir: Option<String>,
+
+    /// Simulate encoding without modifying files
+    #[arg(long, global = true, conflicts_with = "no_dry_run")]
+    pub dry_run: bool,
+
+    /// Encode and modify files even if the config enables dry_run
+    #[arg(long, global = true)]
+    pub no_dry_run: bool,
+
+    /// Recursively search the source directory
+    #[arg(long, global = true, conflicts_with = "no_recursive")]
+    pub recursive: bool,
+
+    /// Only search the top level of the source directory
+    #[arg(long, global = true)]
+    pub no_recursive: bool,
+
+    /// Override the maximum search depth
+    #[arg(long, global = true, value_name = "DEPTH")]
+    pub max_depth: Option<u32>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(source_dir) = &self.source_dir {
+            config.streamline.source_directory = source_dir.clone();
+        }
+        if self.dry_run {
+            config.streamline.dry_run = true;
+        }
+        if self.no_dry_run {
+            config.streamline.dry_run = false;
+        }
+        if self.recursive {
+            config.streamline.recursive = true;
+        }
+        if self.no_recursive {
+            config.streamline.recursive = false;
+        }
+        if let Some(max_depth) = self.max_depth {
+            config.streamline.max_depth = max_depth;
+        }
+    }
+}
@@ -0,0 +1,756 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::models::config::Config;
+use crate::models::file::MediaFile;
+use crate::models::media::{FFProbeOutput, Stream};
+use crate::stream_plan::codec_for_encoder;
+use crate::utils;
+
+/// Subtitle codecs that ffmpeg can convert between.
+const TEXT_SUBTITLE_CODECS: [&str; 6] = ["subrip", "ass", "ssa", "webvtt", "mov_text", "text"];
+
+/// Whether a subtitle stream of `codec` can be muxed into `format`. Unknown formats
+/// are assumed to take anything.
+fn container_supports_subtitle(format: &str, codec: &str) -> bool {
+    match format {
+        "matroska" => codec != "mov_text",
+        "webm" => codec == "webvtt",
+        "mp4" | "mov" | "ipod" => matches!(codec, "mov_text" | "dvd_subtitle"),
+        _ => true,
+    }
+}
+
+/// A single target a file fails to meet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Reason {
+    ForceFilter,
+    VideoCodec {
+        stream: u32,
+        codec: String,
+        allowed: Vec<String>,
+    },
+    Resolution {
+        stream: u32,
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+    FrameRate {
+        stream: u32,
+        fps: f64,
+        max_fps: f64,
+    },
+    Bitrate {
+        stream: u32,
+        bit_rate: u32,
+        max_bitrate: u32,
+    },
+    Hdr {
+        stream: u32,
+        transfer: String,
+    },
+    DolbyVision {
+        stream: u32,
+        profile: u8,
+        compatibility_id: u8,
+        policy: String,
+    },
+    AudioCodec {
+        stream: u32,
+        codec: String,
+        allowed: Vec<String>,
+    },
+    SampleRate {
+        stream: u32,
+        sample_rate: u32,
+        allowed: Vec<u32>,
+    },
+    AudioLanguage {
+        stream: u32,
+        language: String,
+        allowed: Vec<String>,
+    },
+    SubtitleLanguage {
+        stream: u32,
+        language: String,
+        allowed: Vec<String>,
+    },
+    /// A subtitle codec that is not allowed or that the output format can't hold, with
+    /// the codec it is converted to, or `None` if it is dropped
+    SubtitleCodec {
+        stream: u32,
+        codec: String,
+        allowed: Vec<String>,
+        format: String,
+        target: Option<String>,
+    },
+    FileSize {
+        size: u64,
+        target_size: u64,
+    },
+    Container {
+        format: String,
+        extension: String,
+        target_format: String,
+        target_extension: String,
+    },
+}
+
+impl Reason {
+    pub fn rule(&self) -> &'static str {
+        match self {
+            Reason::ForceFilter => "force_filter",
+            Reason::VideoCodec { .. } => "video_codec",
+            Reason::Resolution { .. } => "resolution",
+            Reason::FrameRate { .. } => "frame_rate",
+            Reason::Bitrate { .. } => "bitrate",
+            Reason::Hdr { .. } => "hdr",
+            Reason::DolbyVision { .. } => "dolby_vision",
+            Reason::AudioCodec { .. } => "audio_codec",
+            Reason::SampleRate { .. } => "sample_rate",
+            Reason::AudioLanguage { .. } => "audio_language",
+            Reason::SubtitleLanguage { .. } => "subtitle_language",
+            Reason::SubtitleCodec { .. } => "subtitle_codec",
+            Reason::FileSize { .. } => "file_size",
+            Reason::Container { .. } => "container",
+        }
+    }
+
+    /// The input stream the reason applies to, if it is stream specific.
+    pub fn stream(&self) -> Option<u32> {
+        match self {
+            Reason::ForceFilter | Reason::FileSize { .. } | Reason::Container { .. } => None,
+            Reason::VideoCodec { stream, .. }
+            | Reason::Resolution { stream, .. }
+            | Reason::FrameRate { stream, .. }
+            | Reason::Bitrate { stream, .. }
+            | Reason::Hdr { stream, .. }
+            | Reason::DolbyVision { stream, .. }
+            | Reason::AudioCodec { stream, .. }
+            | Reason::SampleRate { stream, .. }
+            | Reason::AudioLanguage { stream, .. }
+            | Reason::SubtitleLanguage { stream, .. }
+            | Reason::SubtitleCodec { stream, .. } => Some(*stream),
+        }
+    }
+
+    /// Whether the reason can only be resolved by re-encoding, rather than by
+    /// remuxing or dropping streams.
+    pub fn requires_transcode(&self) -> bool {
+        match self {
+            Reason::ForceFilter
+            | Reason::VideoCodec { .. }
+            | Reason::Resolution { .. }
+            | Reason::FrameRate { .. }
+            | Reason::Bitrate { .. }
+            | Reason::Hdr { .. }
+            | Reason::AudioCodec { .. }
+            | Reason::SampleRate { .. }
+            | Reason::FileSize { .. } => true,
+            Reason::DolbyVision { policy, .. } => policy == "strip",
+            Reason::AudioLanguage { .. }
+            | Reason::SubtitleLanguage { .. }
+            | Reason::SubtitleCodec { .. }
+            | Reason::Container { .. } => false,
+        }
+    }
+
+    /// Whether the reason excludes the whole file from processing.
+    pub fn skips_file(&self) -> bool {
+        matches!(self, Reason::DolbyVision { policy, .. } if policy == "skip")
+    }
+
+    /// Whether the reason is only reported, without needing any processing itself.
+    pub fn is_informational(&self) -> bool {
+        matches!(self, Reason::DolbyVision { policy, .. } if policy == "remux")
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::ForceFilter => write!(f, "Force filter enabled - processing all files"),
+            Reason::VideoCodec { codec, allowed, .. } => {
+                write!(f, "Codec: {} not in target list {:?}", codec, allowed)
+            }
+            Reason::Resolution {
+                width,
+                height,
+                max_width,
+                max_height,
+                ..
+            } => write!(
+                f,
+                "Resolution {}x{} exceeds target {}x{}",
+                width, height, max_width, max_height
+            ),
+            Reason::FrameRate { fps, max_fps, .. } => {
+                write!(f, "FPS: {} exceeds target {}", fps, max_fps)
+            }
+            Reason::Bitrate {
+                bit_rate,
+                max_bitrate,
+                ..
+            } => write!(f, "Bitrate: {} exceeds target {}", bit_rate, max_bitrate),
+            Reason::Hdr { transfer, .. } => {
+                write!(f, "HDR: {} video will be tonemapped to SDR", transfer)
+            }
+            Reason::DolbyVision {
+                profile,
+                compatibility_id,
+                policy,
+                ..
+            } => {
+                write!(f, "Dolby Vision profile {}.{}: ", profile, compatibility_id)?;
+                match policy.as_str() {
+                    "skip" => write!(f, "file skipped"),
+                    "strip" => write!(f, "video will be re-encoded to its HDR10 base layer"),
+                    _ => write!(f, "video will only be remuxed"),
+                }
+            }
+            Reason::AudioCodec { codec, allowed, .. } => {
+                write!(f, "Audio codec: {} not in target list {:?}", codec, allowed)
+            }
+            Reason::SampleRate {
+                sample_rate,
+                allowed,
+                ..
+            } => write!(
+                f,
+                "Sample rate: {} not in target list {:?}",
+                sample_rate, allowed
+            ),
+            Reason::AudioLanguage {
+                language, allowed, ..
+            } => write!(
+                f,
+                "Audio language: {} not in target list {:?}",
+                language, allowed
+            ),
+            Reason::SubtitleLanguage {
+                language, allowed, ..
+            } => write!(
+                f,
+                "Subtitle language: {} not in target list {:?}",
+                language, allowed
+            ),
+            Reason::SubtitleCodec {
+                codec,
+                allowed,
+                format,
+                target,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Subtitle codec: {} not in target list {:?} or not supported by {}, ",
+                    codec, allowed, format
+                )?;
+                match target {
+                    Some(target) => write!(f, "will be converted to {}", target),
+                    None => write!(f, "will be dropped"),
+                }
+            }
+            Reason::FileSize { size, target_size } => write!(
+                f,
+                "File size: {} MB exceeds target {} MB",
+                size / 1_000_000,
+                target_size / 1_000_000
+            ),
+            Reason::Container {
+                format,
+                extension,
+                target_format,
+                target_extension,
+            } => write!(
+                f,
+                "Container: {} (.{}) is not {} (.{})",
+                format, extension, target_format, target_extension
+            ),
+        }
+    }
+}
+
+/// Every reason a file needs processing, along with the profile used to decide.
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub path: PathBuf,
+    pub profile: Option<String>,
+    pub reasons: Vec<Reason>,
+}
+
+/// Decides which media files fall outside the configured targets.
+pub struct DecisionEngine<'a> {
+    config: &'a Config,
+}
+
+impl<'a> DecisionEngine<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        DecisionEngine { config }
+    }
+
+    fn debug_print_ln(&self, s: &str) {
+        if self.config.streamline.debug {
+            println!("{}", s);
+        }
+    }
+
+    pub fn needs_processing(&self, file: &MediaFile) -> bool {
+        let reasons = self.evaluate(file);
+        for reason in &reasons {
+            self.debug_print_ln(&reason.to_string());
+        }
+        if reasons.iter().any(Reason::skips_file) {
+            return false;
+        }
+        reasons.iter().any(|reason| {
+            !reason.is_informational()
+                && (reason.requires_transcode() || self.config.streamline.remux_mismatches)
+        })
+    }
+
+    pub fn explain(&self, file: &MediaFile) -> Explanation {
+        Explanation {
+            path: file.path.clone(),
+            profile: self
+                .config
+                .profile_for(&file.path)
+                .map(|profile| profile.name.clone()),
+            reasons: self.evaluate(file),
+        }
+    }
+
+    /// Returns every target `file` fails to meet, using the profile matching its path.
+    pub fn evaluate(&self, file: &MediaFile) -> Vec<Reason> {
+        match self.config.for_file(&file.path) {
+            Ok(config) => {
+                if let Some(profile) = self.config.profile_for(&file.path) {
+                    self.debug_print_ln(&format!("Using profile: {}", profile.name));
+                }
+                DecisionEngine::new(&config).check_targets(file)
+            }
+            Err(e) => {
+                println!("Error resolving profile for {}: {}", file.path.display(), e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn check_targets(&self, file: &MediaFile) -> Vec<Reason> {
+        let mut reasons = Vec::new();
+
+        if self.config.video_targets.force_filter || self.config.audio_targets.force_filter {
+            reasons.push(Reason::ForceFilter);
+        }
+
+        if let Some(reason) = self.check_container(file) {
+            reasons.push(reason);
+        }
+
+        if let Some(reason) = self.check_file_size(&file.info) {
+            reasons.push(reason);
+        }
+
+        for stream in file.info.get_streams_of_type("video") {
+            reasons.extend(self.check_video_stream(stream));
+        }
+
+        for stream in file.info.get_streams_of_type("audio") {
+            reasons.extend(self.check_audio_stream(stream));
+        }
+
+        for stream in file.info.get_streams_of_type("subtitle") {
+            reasons.extend(self.check_subtitle_stream(stream));
+        }
+
+        reasons
+    }
+
+    pub fn check_video_stream(&self, stream: &Stream) -> Vec<Reason> {
+        let video_targets = &self.config.video_targets;
+        let mut reasons = Vec::new();
+
+        if let Some(dolby_vision) = stream.dolby_vision() {
+            let policy = self.config.dolby_vision.policy_for(dolby_vision.profile);
+            reasons.push(Reason::DolbyVision {
+                stream: stream.index,
+                profile: dolby_vision.profile,
+                compatibility_id: dolby_vision.compatibility_id,
+                policy: policy.to_string(),
+            });
+            // The video is left alone, so the other targets don't apply to it
+            if policy != "strip" {
+                return reasons;
+            }
+        }
+
+        if !video_targets.codec.is_empty() && !video_targets.codec.contains(&stream.codec_name) {
+            reasons.push(Reason::VideoCodec {
+                stream: stream.index,
+                codec: stream.codec_name.clone(),
+                allowed: video_targets.codec.clone(),
+            });
+        }
+
+        let width = stream.width.unwrap_or(0);
+        let height = stream.height.unwrap_or(0);
+        if (video_targets.max_height != 0 && height > video_targets.max_height)
+            || (video_targets.max_width != 0 && width > video_targets.max_width)
+        {
+            reasons.push(Reason::Resolution {
+                stream: stream.index,
+                width,
+                height,
+                max_width: video_targets.max_width,
+                max_height: video_targets.max_height,
+            });
+        }
+
+        if video_targets.max_fps != 0.0 && stream.avg_frame_rate > video_targets.max_fps {
+            reasons.push(Reason::FrameRate {
+                stream: stream.index,
+                fps: stream.avg_frame_rate,
+                max_fps: video_targets.max_fps,
+            });
+        }
+
+        if video_targets.max_bitrate != 0 && stream.bit_rate > video_targets.max_bitrate {
+            reasons.push(Reason::Bitrate {
+                stream: stream.index,
+                bit_rate: stream.bit_rate,
+                max_bitrate: video_targets.max_bitrate,
+            });
+        }
+
+        if video_targets.hdr_mode == "tonemap" && stream.is_hdr() {
+            reasons.push(Reason::Hdr {
+                stream: stream.index,
+                transfer: stream.color_transfer.clone().unwrap_or_default(),
+            });
+        }
+
+        reasons
+    }
+
+    pub fn check_audio_stream(&self, stream: &Stream) -> Vec<Reason> {
+        let audio_targets = &self.config.audio_targets;
+        let mut reasons = Vec::new();
+
+        if !audio_targets.codec.is_empty() && !audio_targets.codec.contains(&stream.codec_name) {
+            reasons.push(Reason::AudioCodec {
+                stream: stream.index,
+                codec: stream.codec_name.clone(),
+                allowed: audio_targets.codec.clone(),
+            });
+        }
+
+        if !audio_targets.sample_rate.is_empty()
+            && !audio_targets.sample_rate.contains(&stream.sample_rate)
+        {
+            reasons.push(Reason::SampleRate {
+                stream: stream.index,
+                sample_rate: stream.sample_rate,
+                allowed: audio_targets.sample_rate.clone(),
+            });
+        }
+
+        if !audio_targets.language.is_empty()
+            && !stream.tags.language.is_empty()
+            && !audio_targets.language.contains(&stream.tags.language)
+        {
+            reasons.push(Reason::AudioLanguage {
+                stream: stream.index,
+                language: stream.tags.language.clone(),
+                allowed: audio_targets.language.clone(),
+            });
+        }
+
+        reasons
+    }
+
+    pub fn check_subtitle_stream(&self, stream: &Stream) -> Vec<Reason> {
+        let subtitles = &self.config.subtitles;
+        let mut reasons = Vec::new();
+
+        if !subtitles.language.is_empty()
+            && !stream.tags.language.is_empty()
+            && !subtitles.language.contains(&stream.tags.language)
+        {
+            reasons.push(Reason::SubtitleLanguage {
+                stream: stream.index,
+                language: stream.tags.language.clone(),
+                allowed: subtitles.language.clone(),
+            });
+        }
+
+        if let Some(reason) = self.check_subtitle_codec(stream) {
+            reasons.push(reason);
+        }
+
+        reasons
+    }
+
+    /// Subtitles are copied when their codec is allowed and the output format can hold
+    /// them. Otherwise text subtitles are converted to the first allowed codec the output
+    /// format supports, and image subtitles are copied anyway, unless `drop_images` is set,
+    /// as they can't be converted.
+    fn check_subtitle_codec(&self, stream: &Stream) -> Option<Reason> {
+        let subtitles = &self.config.subtitles;
+        let format = &self.config.streamline.output_format;
+        let codec = &stream.codec_name;
+        let allowed = subtitles.copy_subtitles
+            || subtitles.codec.is_empty()
+            || subtitles
+                .codec
+                .iter()
+                .any(|allowed| codec_for_encoder(allowed) == *codec);
+        let supported = container_supports_subtitle(format, codec);
+        if allowed && supported {
+            return None;
+        }
+
+        let target = if TEXT_SUBTITLE_CODECS.contains(&codec.as_str()) {
+            subtitles
+                .codec
+                .iter()
+                .find(|target| container_supports_subtitle(format, &codec_for_encoder(target)))
+                .cloned()
+        } else if supported && !subtitles.drop_images {
+            return None;
+        } else {
+            None
+        };
+        Some(Reason::SubtitleCodec {
+            stream: stream.index,
+            codec: codec.clone(),
+            allowed: subtitles.codec.clone(),
+            format: format.clone(),
+            target,
+        })
+    }
+
+    pub fn check_file_size(&self, info: &FFProbeOutput) -> Option<Reason> {
+        let target_size = self.config.video_targets.target_size_mb as u64 * 1_000_000;
+        let size = info.format.size.parse::<u64>().unwrap_or(0);
+        if target_size == 0 || size <= target_size {
+            return None;
+        }
+        Some(Reason::FileSize { size, target_size })
+    }
+
+    fn check_container(&self, file: &MediaFile) -> Option<Reason> {
+        let streamline = &self.config.streamline;
+        let format = &file.info.format.format_name;
+        let extension = file
+            .path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let format_matches = streamline.output_format.is_empty()
+            || format
+                .split(',')
+                .any(|name| name == streamline.output_format);
+        // Replaced sources keep their name, so their extension never changes
+        let replaced_in_place = streamline.always_replace || streamline.replace_if_smaller;
+        let extension_matches = streamline.output_extension.is_empty()
+            || replaced_in_place
+            || extension == streamline.output_extension;
+        if format_matches && extension_matches {
+            return None;
+        }
+
+        Some(Reason::Container {
+            format: format.clone(),
+            extension,
+            target_format: streamline.output_format.clone(),
+            target_extension: streamline.output_extension.clone(),
+        })
+    }
+
+    pub fn check_files(&self, files: Vec<MediaFile>) -> Vec<MediaFile> {
+        let mut needs_processing = Vec::new();
+
+        let total_files = files.len();
+
+        let pb = utils::create_progress_bar(files.len() as u64, true, 500);
+        pb.set_message("Analyzing files...");
+        for file in files {
+            pb.inc(1);
+            pb.tick();
+
+            if self.needs_processing(&file) {
+                needs_processing.push(file);
+            }
+        }
+
+        utils::set_pb_finish_message(
+            &pb,
+            format!(
+                "✅ Files Analyzed! Found: {}/{} that need processing",
+                needs_processing.len(),
+                total_files
+            ),
+        );
+
+        needs_processing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::media::{Format, Tags};
+
+    fn stream(index: u32, codec_type: &str, codec: &str, language: &str) -> Stream {
+        Stream {
+            index,
+            codec_type: codec_type.to_string(),
+            codec_name: codec.to_string(),
+            width: Some(1920),
+            height: Some(1080),
+            tags: Tags {
+                language: language.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn media_file(path: &str, format_name: &str, streams: Vec<Stream>) -> MediaFile {
+        MediaFile {
+            path: PathBuf::from(path),
+            info: FFProbeOutput {
+                streams,
+                format: Format {
+                    format_name: format_name.to_string(),
+                    size: "0".to_string(),
+                    ..Default::default()
+                },
+            },
+        }
+    }
+
+    fn rules(reasons: &[Reason]) -> Vec<&'static str> {
+        reasons.iter().map(Reason::rule).collect()
+    }
+
+    #[test]
+    fn collects_every_failing_target() {
+        let mut config = Config::for_tests();
+        config.video_targets.max_height = 720;
+        let file = media_file(
+            "/library/movie.avi",
+            "avi",
+            vec![
+                stream(0, "video", "mpeg4", ""),
+                stream(1, "audio", "dts", "fre"),
+                stream(2, "subtitle", "subrip", "fre"),
+            ],
+        );
+
+        let reasons = DecisionEngine::new(&config).evaluate(&file);
+        assert_eq!(
+            rules(&reasons),
+            [
+                "container",
+                "video_codec",
+                "resolution",
+                "audio_codec",
+                "audio_language",
+                "subtitle_language"
+            ]
+        );
+        assert_eq!(reasons[1].stream(), Some(0));
+        assert_eq!(reasons[3].stream(), Some(1));
+
+        let file = media_file(
+            "/library/movie.mkv",
+            "matroska,webm",
+            vec![
+                stream(0, "video", "hevc", ""),
+                stream(1, "audio", "aac", "eng"),
+            ],
+        );
+        config.video_targets.max_height = 0;
+        assert!(DecisionEngine::new(&config).evaluate(&file).is_empty());
+    }
+
+    #[test]
+    fn matching_profile_sets_the_targets() {
+        let mut config = Config::for_tests();
+        config.profiles = vec![toml::from_str(
+            "name = \"anime\"\ndirectories = [\"Anime\"]\nvideo_targets = { codec = [\"hevc\"] }",
+        )
+        .unwrap()];
+        let engine = DecisionEngine::new(&config);
+        let file = media_file(
+            "/library/Anime/show.mkv",
+            "matroska",
+            vec![stream(0, "video", "h264", "")],
+        );
+        let explanation = engine.explain(&file);
+        assert_eq!(explanation.profile.as_deref(), Some("anime"));
+        assert_eq!(rules(&explanation.reasons), ["video_codec"]);
+
+        let file = media_file(
+            "/library/Movies/movie.mkv",
+            "matroska",
+            vec![stream(0, "video", "h264", "")],
+        );
+        let explanation = engine.explain(&file);
+        assert_eq!(explanation.profile, None);
+        assert!(explanation.reasons.is_empty());
+    }
+
+    #[test]
+    fn remuxes_only_when_enabled() {
+        let mut config = Config::for_tests();
+        let file = media_file(
+            "/library/movie.mkv",
+            "matroska",
+            vec![
+                stream(0, "video", "hevc", ""),
+                stream(1, "audio", "aac", "fre"),
+            ],
+        );
+        assert!(!DecisionEngine::new(&config).needs_processing(&file));
+
+        config.streamline.remux_mismatches = true;
+        assert!(DecisionEngine::new(&config).needs_processing(&file));
+
+        config.streamline.remux_mismatches = false;
+        let file = media_file(
+            "/library/movie.mkv",
+            "matroska",
+            vec![stream(0, "video", "vp9", "")],
+        );
+        assert!(DecisionEngine::new(&config).needs_processing(&file));
+    }
+
+    #[test]
+    fn replaced_files_keep_their_extension() {
+        let mut config = Config::for_tests();
+        let file = media_file(
+            "/library/movie.mp4",
+            "matroska,webm",
+            vec![stream(0, "video", "hevc", "")],
+        );
+        assert_eq!(
+            DecisionEngine::new(&config).evaluate(&file),
+            [Reason::Container {
+                format: "matroska,webm".to_string(),
+                extension: "mp4".to_string(),
+                target_format: "matroska".to_string(),
+                target_extension: "mkv".to_string(),
+            }]
+        );
+
+        config.streamline.always_replace = true;
+        assert!(DecisionEngine::new(&config).evaluate(&file).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use indicatif::ProgressBar;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::models::config::Config;
+use crate::utils;
+
+/// Finds the media files in the configured source directory.
+pub struct Scanner<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Scanner { config }
+    }
+
+    pub fn collect_files(&self) -> io::Result<Vec<PathBuf>> {
+        let collection_spinner = utils::create_spinner(false);
+        let files = self.collect_files_with_extensions(
+            Path::new(&self.config.streamline.source_directory),
+            0,
+            &collection_spinner,
+        )?;
+        collection_spinner
+            .finish_with_message(format!("✅ Files collected! Found: {}", files.len()));
+        Ok(files)
+    }
+
+    fn collect_files_with_extensions(
+        &self,
+        path: &Path,
+        depth: u32,
+        spinner: &ProgressBar,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        let streamline = &self.config.streamline;
+
+        if self.is_excluded_directory(path) {
+            return Ok(files);
+        }
+
+        if depth <= streamline.max_depth {
+            let objs = fs::read_dir(path)?;
+            spinner.set_message(format!("Collecting files... Searching {}", path.display()));
+
+            for obj in objs {
+                let obj = obj?;
+                let path = obj.path();
+                spinner.tick();
+
+                if path.is_file() {
+                    if self.has_media_extension(&path) {
+                        files.push(path);
+                    }
+                } else if streamline.recursive && path.is_dir() {
+                    let sub_files =
+                        self.collect_files_with_extensions(&path, depth + 1, spinner)?;
+                    files.extend(sub_files);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn is_excluded_directory(&self, path: &Path) -> bool {
+        let streamline = &self.config.streamline;
+        if streamline
+            .exclude_directories
+            .iter()
+            .any(|dir| path.ends_with(dir))
+        {
+            return true;
+        }
+
+        // Never pick up originals waiting in a recycle directory inside the library
+        !streamline.recycle_directory.is_empty() && path == Path::new(&streamline.recycle_directory)
+    }
+
+    fn has_media_extension(&self, path: &Path) -> bool {
+        match path.extension() {
+            Some(ext) => self
+                .config
+                .streamline
+                .file_extensions
+                .contains(&ext.to_string_lossy().into_owned()),
+            None => false,
+        }
+    }
+
+    /// Whether `path` is a file `collect_files` would find, without searching the
+    /// source directory. Encodes still being written are never included.
+    pub fn is_media_file(&self, path: &Path) -> bool {
+        let streamline = &self.config.streamline;
+        let relative = match path.strip_prefix(&streamline.source_directory) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let directories = relative.components().count().saturating_sub(1);
+        if directories as u32 > streamline.max_depth || (!streamline.recursive && directories > 0) {
+            return false;
+        }
+        if path
+            .ancestors()
+            .skip(1)
+            .take(directories + 1)
+            .any(|directory| self.is_excluded_directory(directory))
+        {
+            return false;
+        }
+
+        let is_temporary = path.file_name().is_some_and(|name| {
+            name.to_string_lossy()
+                .contains(&format!("{}-", streamline.temporary_suffix))
+        });
+        !is_temporary
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            && self.has_media_extension(path)
+            && path.is_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_media_files() {
+        let source =
+            std::env::temp_dir().join(format!("streamline-discovery-{}", std::process::id()));
+        for file in [
+            "a.mkv",
+            "a.txt",
+            "a_temp-0123456789abcdef.mkv",
+            "extras/b.mkv",
+            "Show/Season 1/c.mkv",
+        ] {
+            let path = source.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let mut config = Config::for_tests();
+        config.streamline.source_directory = source.to_string_lossy().into_owned();
+        let scanner = Scanner::new(&config);
+
+        assert!(scanner.is_media_file(&source.join("a.mkv")));
+        assert!(scanner.is_media_file(&source.join("Show/Season 1/c.mkv")));
+        assert!(!scanner.is_media_file(&source.join("a.txt")));
+        assert!(!scanner.is_media_file(&source.join("a_temp-0123456789abcdef.mkv")));
+        assert!(!scanner.is_media_file(&source.join("extras/b.mkv")));
+        assert!(!scanner.is_media_file(&source.join("missing.mkv")));
+        assert!(!scanner.is_media_file(Path::new("/elsewhere/a.mkv")));
+
+        config.streamline.recursive = false;
+        let scanner = Scanner::new(&config);
+        assert!(scanner.is_media_file(&source.join("a.mkv")));
+        assert!(!scanner.is_media_file(&source.join("Show/Season 1/c.mkv")));
+
+        config.streamline.recursive = true;
+        config.streamline.max_depth = 1;
+        let scanner = Scanner::new(&config);
+        assert!(!scanner.is_media_file(&source.join("Show/Season 1/c.mkv")));
+
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
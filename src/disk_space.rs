@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::models::config::Config;
+use crate::plan::{Job, Replacement};
+
+/// How often a paused job checks the free space again, when no other job finishes first.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tracks the space set aside for running jobs on each filesystem, so concurrent jobs
+/// cannot all claim the same free space.
+pub struct DiskSpace<'a> {
+    config: &'a Config,
+    /// Reserved bytes by filesystem id
+    reserved: Mutex<HashMap<u64, u64>>,
+    released: Condvar,
+}
+
+/// Space reserved for a job, released when dropped.
+pub struct Reservation<'a> {
+    space: Option<&'a DiskSpace<'a>>,
+    needs: Vec<(u64, u64)>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(space) = self.space {
+            let mut reserved = space.reserved.lock().unwrap();
+            for (filesystem, bytes) in &self.needs {
+                if let Some(total) = reserved.get_mut(filesystem) {
+                    *total = total.saturating_sub(*bytes);
+                }
+            }
+            space.released.notify_all();
+        }
+    }
+}
+
+/// Space a job needs on one filesystem.
+struct Need {
+    filesystem: u64,
+    directory: PathBuf,
+    bytes: u64,
+}
+
+impl<'a> DiskSpace<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        DiskSpace {
+            config,
+            reserved: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// An estimate of the size of the encode of `job`. Encodes are expected to come out
+    /// smaller than their source, so the size of the input is used as an upper bound.
+    pub fn estimate_output_size(job: &Job) -> u64 {
+        fs::metadata(&job.input)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    /// Reserves the space `job` needs on the temporary and output filesystems. When that
+    /// would leave less than `min_free_space_mb` free, the job either waits for running
+    /// jobs to release their space or fails, depending on `low_space_action`. It always
+    /// fails when no running job has space reserved there.
+    pub fn reserve(&'a self, job: &Job) -> Result<Reservation<'a>, String> {
+        if self.config.streamline.dry_run {
+            return Ok(Reservation {
+                space: None,
+                needs: Vec::new(),
+            });
+        }
+        let needs = self.needs(job)?;
+        let floor = self.config.streamline.min_free_space_mb * 1_000_000;
+
+        let mut reserved = self.reserved.lock().unwrap();
+        let mut waiting = false;
+        loop {
+            let mut shortfall = None;
+            for need in &needs {
+                let available = fs2::available_space(&need.directory).map_err(|e| {
+                    format!(
+                        "Error checking free space ({}): {}",
+                        need.directory.display(),
+                        e
+                    )
+                })?;
+                let claimed = reserved.get(&need.filesystem).copied().unwrap_or(0);
+                if available.saturating_sub(claimed) < need.bytes + floor {
+                    shortfall = Some((need, available.saturating_sub(claimed), claimed));
+                    break;
+                }
+            }
+
+            let (need, free, claimed) = match shortfall {
+                Some(shortfall) => shortfall,
+                None => break,
+            };
+            let message = format!(
+                "Not enough free space for {} on {}: {} MB needed, {} MB free after the {} MB floor",
+                job.input.display(),
+                need.directory.display(),
+                need.bytes / 1_000_000,
+                free.saturating_sub(floor) / 1_000_000,
+                floor / 1_000_000
+            );
+            // With nothing else running on that filesystem, waiting would never end
+            if self.config.streamline.low_space_action == "skip" || claimed == 0 {
+                return Err(message);
+            }
+            if !waiting {
+                println!("{}, waiting", message);
+                waiting = true;
+            }
+            reserved = self
+                .released
+                .wait_timeout(reserved, RECHECK_INTERVAL)
+                .unwrap()
+                .0;
+        }
+
+        for need in &needs {
+            *reserved.entry(need.filesystem).or_insert(0) += need.bytes;
+        }
+        Ok(Reservation {
+            space: Some(self),
+            needs: needs
+                .iter()
+                .map(|need| (need.filesystem, need.bytes))
+                .collect(),
+        })
+    }
+
+    /// The space `job` takes up while it runs: its encode and staged input in the
+    /// temporary directory, and a copy of the encode where it ends up when that is
+    /// on another filesystem.
+    fn needs(&self, job: &Job) -> Result<Vec<Need>, String> {
+        let estimate = Self::estimate_output_size(job);
+        let staged = match job.staged_input {
+            Some(_) => fs::metadata(&job.input)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            None => 0,
+        };
+        let destination = match job.replacement {
+            Replacement::Never => &job.output,
+            Replacement::Always | Replacement::IfSmaller => &job.input,
+        };
+
+        let mut needs: Vec<Need> = Vec::new();
+        for (path, bytes) in [
+            (&job.temp_output, estimate + staged),
+            (destination, estimate),
+        ] {
+            let directory = existing_ancestor(path);
+            let filesystem = filesystem_id(&directory)
+                .map_err(|e| format!("Error checking free space ({}): {}", path.display(), e))?;
+            // Moving within a filesystem is a rename, which takes no extra space
+            if needs.iter().any(|need| need.filesystem == filesystem) {
+                continue;
+            }
+            needs.push(Need {
+                filesystem,
+                directory,
+                bytes,
+            });
+        }
+        Ok(needs)
+    }
+}
+
+/// The closest directory containing `path` that exists, as output directories are
+/// only created once needed.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .skip(1)
+        .find(|ancestor| ancestor.is_dir())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+#[cfg(unix)]
+fn filesystem_id(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path)?.dev())
+}
+
+/// Elsewhere, paths on the same drive or share are assumed to share a filesystem.
+#[cfg(not(unix))]
+fn filesystem_id(path: &Path) -> io::Result<u64> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let path = fs::canonicalize(path)?;
+    let mut hasher = DefaultHasher::new();
+    path.components().next().hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("streamline-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn job(directory: &Path, output: PathBuf, replacement: Replacement) -> Job {
+        let input = directory.join("movie.mkv");
+        fs::write(&input, vec![0; 1000]).unwrap();
+        Job {
+            input,
+            staged_input: None,
+            profile: None,
+            reasons: Vec::new(),
+            program: "ffmpeg".to_string(),
+            args: Vec::new(),
+            temp_output: directory.join("movie_temp.mkv"),
+            output,
+            replacement,
+            duration_secs: 0.0,
+            streams: Default::default(),
+            first_pass_args: None,
+            passlog: None,
+        }
+    }
+
+    fn needed_bytes(config: &Config, job: &Job) -> Vec<u64> {
+        let needs = DiskSpace::new(config).needs(job).unwrap();
+        needs.iter().map(|need| need.bytes).collect()
+    }
+
+    #[test]
+    fn counts_one_filesystem_once() {
+        let directory = test_directory("disk-space-same");
+        let config = Config::for_tests();
+        // Not created yet, so its closest existing parent is checked
+        let output = directory.join("Movies/movie.mkv");
+        let mut job = job(&directory, output, Replacement::Never);
+
+        assert_eq!(needed_bytes(&config, &job), [1000]);
+        let needs = DiskSpace::new(&config).needs(&job).unwrap();
+        assert_eq!(needs[0].directory, directory);
+
+        // The staged copy sits next to the encode
+        job.staged_input = Some(directory.join("staged.mkv"));
+        assert_eq!(needed_bytes(&config, &job), [2000]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn destination_follows_replacement() {
+        let directory = test_directory("disk-space-other");
+        let other = Path::new("/dev/shm");
+        // Needs a second filesystem, which not every machine has
+        let same_filesystem = filesystem_id(&directory).ok() == filesystem_id(other).ok();
+        if !other.is_dir() || same_filesystem {
+            fs::remove_dir_all(directory).unwrap();
+            return;
+        }
+        let config = Config::for_tests();
+
+        // The encode is copied to the output on the other filesystem
+        let mut job = job(&directory, other.join("movie.mkv"), Replacement::Never);
+        assert_eq!(needed_bytes(&config, &job), [1000, 1000]);
+
+        // Replacing the source is a rename next to the encode
+        job.replacement = Replacement::Always;
+        assert_eq!(needed_bytes(&config, &job), [1000]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,347 +1,1343 @@
-use crate::models::config::CONFIG;
+use crate::decision::DecisionEngine;
+use crate::disk_space::{DiskSpace, Reservation};
+use crate::filter_graph::{FilterChain, FilterGraph, FilterNode};
+use crate::journal::{JobState, Journal};
+use crate::models::config::Config;
 use crate::models::file::MediaFile;
 use crate::models::media::Stream;
+use crate::plan::{Job, Plan, Replacement};
+use crate::progress;
+use crate::quality::QualityChecker;
+use crate::recycle::RecycleBin;
+use crate::stream_plan::{StreamAction, StreamPlan};
 use crate::utils;
-use std::path::Path;
+use crate::verify::Verifier;
+use indicatif::{MultiProgress, ProgressBar};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-pub fn check_ffmpeg() -> Result<(), String> {
-    match Command::new(&CONFIG.ffmpeg.ffmpeg_path)
-        .arg("-version")
-        .output()
-    {
-        Ok(_) => Ok(()),
-        Err(_) => Err("FFmpeg not found!".to_string()),
-    }
+/// A job along with its reserved disk space and staged input, or why they could not
+/// be set up.
+type PreparedJob<'a> = (Job, Result<Reservation<'a>, String>);
+
+/// Share of a target file size reserved for the container's own overhead.
+const CONTAINER_OVERHEAD: f64 = 0.02;
+/// Audio bitrate per channel assumed when the source doesn't report one.
+const FALLBACK_CHANNEL_BITRATE: u64 = 64_000;
+/// Below this, a target size is treated as unreachable rather than encoded into mush.
+const MIN_VIDEO_BITRATE: u32 = 100_000;
+
+/// Builds and runs the ffmpeg commands that bring files in line with the targets.
+pub struct Processor<'a> {
+    config: &'a Config,
+    journal: Option<&'a Mutex<Journal>>,
+    progress: Option<&'a MultiProgress>,
+    space: Option<&'a DiskSpace<'a>>,
 }
 
-pub fn get_output_path(input_file: &Path) -> String {
-    let mut working_path = input_file
-        .with_extension(&CONFIG.streamline.output_extension)
-        .to_str()
-        .unwrap()
-        .to_string();
-    if !CONFIG.streamline.output_directory.is_empty() {
-        working_path = working_path
-            .split(&utils::get_system_path_separator())
-            .last()
-            .unwrap()
-            .to_string();
-        working_path = format!(
-            "{}{}{}",
-            &CONFIG.streamline.output_directory,
-            utils::get_system_path_separator(),
-            working_path
-        );
+impl<'a> Processor<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Processor {
+            config,
+            journal: None,
+            progress: None,
+            space: None,
+        }
     }
-    format!("{}.{}", working_path, &CONFIG.streamline.temporary_suffix)
-}
 
-fn apply_aspect_ratio_corrections(stream: &Stream, filters: &mut Vec<String>) {
-    if stream.width.is_none() || stream.height.is_none() {
-        // No width or height, nothing to do
-        return;
+    pub fn check_ffmpeg(&self) -> Result<(), String> {
+        match Command::new(&self.config.ffmpeg.ffmpeg_path)
+            .arg("-version")
+            .output()
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err("FFmpeg not found!".to_string()),
+        }
     }
-    if !CONFIG.video_targets.max_width != 0 && !CONFIG.video_targets.max_height != 0 {
-        // No max width or height, nothing to do
-        return;
+
+    /// Where the output for `input_file` ends up when the source is not replaced: next
+    /// to the source, or at its path relative to `source_directory` under `output_directory`.
+    pub fn get_output_path(&self, input_file: &Path) -> PathBuf {
+        let streamline = &self.config.streamline;
+        let output = input_file.with_extension(&streamline.output_extension);
+        if streamline.output_directory.is_empty() {
+            return output;
+        }
+
+        let output_directory = Path::new(&streamline.output_directory);
+        match output.strip_prefix(&streamline.source_directory) {
+            Ok(relative) => output_directory.join(relative),
+            // Outside the source directory, such as a plan made with another config
+            Err(_) => output_directory.join(output.file_name().unwrap_or_default()),
+        }
     }
 
-    let width = stream.width.unwrap();
-    let height = stream.height.unwrap();
-    if width <= CONFIG.video_targets.max_width && height <= CONFIG.video_targets.max_height {
-        // Video is already within target dimensions, nothing to do
-        return;
+    /// Where `input_file` is encoded to before being moved into place: `temp_directory`,
+    /// or next to the output when it is blank. Unique per input path, as several inputs
+    /// may share an output name.
+    fn get_temp_output_path(&self, input_file: &Path, output: &Path) -> PathBuf {
+        let streamline = &self.config.streamline;
+        let directory = match streamline.temp_directory.is_empty() {
+            true => output.parent().unwrap_or(Path::new("")),
+            false => Path::new(&streamline.temp_directory),
+        };
+        let stem = output
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        directory.join(format!(
+            "{}{}-{:016x}.{}",
+            stem,
+            streamline.temporary_suffix,
+            path_hash(input_file),
+            streamline.output_extension
+        ))
     }
 
-    let aspect_ratio = width as f32 / height as f32;
-    let target_aspect_ratio =
-        CONFIG.video_targets.max_width as f32 / CONFIG.video_targets.max_height as f32;
+    /// Scales videos larger than `max_width`/`max_height` down to fit, preserving their
+    /// display aspect ratio. Videos are never scaled up.
+    fn apply_scaling(&self, stream: &Stream, filters: &mut FilterChain) {
+        let (width, height) = match (stream.width, stream.height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            // No width or height, nothing to do
+            _ => return,
+        };
+        let max_width = self.config.video_targets.max_width;
+        let max_height = self.config.video_targets.max_height;
+        if (max_width == 0 || width <= max_width) && (max_height == 0 || height <= max_height) {
+            // Video is already within target dimensions, nothing to do
+            return;
+        }
+
+        // Scale the display size, so anamorphic sources come out with square pixels
+        let display_width = width as f64 * stream.pixel_aspect_ratio();
+        let display_height = height as f64;
+        let mut factor: f64 = 1.0;
+        if max_width != 0 {
+            factor = factor.min(max_width as f64 / display_width);
+        }
+        if max_height != 0 {
+            factor = factor.min(max_height as f64 / display_height);
+        }
 
-    let mut pad_width = 0;
-    let mut pad_height = 0;
+        filters.push(FilterNode::Scale {
+            width: even((display_width * factor).round() as u32),
+            height: even((display_height * factor).round() as u32),
+            algorithm: self.config.video_targets.scaler.clone(),
+        });
 
-    if aspect_ratio > target_aspect_ratio {
-        pad_height = CONFIG.video_targets.max_height - height;
-    } else {
-        pad_width = CONFIG.video_targets.max_width - width;
+        if self.config.video_targets.letterbox && max_width != 0 && max_height != 0 {
+            filters.push(FilterNode::Letterbox {
+                width: even(max_width),
+                height: even(max_height),
+            });
+        }
     }
 
-    filters.push(format!(
-        "pad={}:{}:{}:{}",
-        CONFIG.video_targets.max_width,
-        CONFIG.video_targets.max_height,
-        pad_width / 2,
-        pad_height / 2
-    ));
-}
+    /// Encoder options for transcoding `stream` with `encoder` to the output stream at
+    /// `specifier`. `pass` is the pass number and log file of a two-pass encode.
+    fn apply_video_arguments(
+        &self,
+        stream: &Stream,
+        encoder: &str,
+        specifier: &str,
+        pass: Option<(u32, &Path)>,
+        command: &mut Command,
+    ) {
+        let is_x265 = matches!(encoder, "hevc" | "h265" | "libx265");
+
+        // CRF would take precedence over the bitrate a two-pass encode is aiming for
+        if self.config.video_targets.crf != -1 && pass.is_none() {
+            command
+                .arg(format!("-crf:{}", specifier))
+                .arg(self.config.video_targets.crf.to_string());
+        }
+
+        if !self.config.video_targets.ffmpeg_preset.is_empty() {
+            command
+                .arg(format!("-preset:{}", specifier))
+                .arg(&self.config.video_targets.ffmpeg_preset);
+        }
+
+        self.apply_color_arguments(stream, specifier, command);
+
+        if !self.config.video_targets.tune.is_empty() {
+            command
+                .arg(format!("-tune:{}", specifier))
+                .arg(&self.config.video_targets.tune);
+        }
 
-fn apply_video_arguments(info: &Stream, command: &mut Command) {
-    if !CONFIG.video_targets.codec.contains(&info.codec_name) {
-        command.arg("-c:v").arg(&CONFIG.video_targets.codec[0]);
+        let mut x265_params = Vec::new();
+        if !self.config.video_targets.x265_params.is_empty() {
+            x265_params.push(self.config.video_targets.x265_params.clone());
+        }
+        if is_x265 && stream.is_hdr() && self.config.video_targets.hdr_mode == "preserve" {
+            x265_params.extend(hdr10_x265_params(stream));
+        }
+        if let Some((pass, passlog)) = pass {
+            // x265 ignores -pass, and takes its pass settings as encoder parameters
+            if is_x265 {
+                x265_params.push(format!("pass={}", pass));
+                x265_params.push(format!(
+                    "stats={}",
+                    x265_param_value(&passlog.to_string_lossy())
+                ));
+            } else {
+                command
+                    .arg(format!("-pass:{}", specifier))
+                    .arg(pass.to_string())
+                    .arg(format!("-passlogfile:{}", specifier))
+                    .arg(passlog);
+            }
+        }
+        if !x265_params.is_empty() {
+            command
+                .arg(format!("-x265-params:{}", specifier))
+                .arg(x265_params.join(":"));
+        }
     }
 
-    if CONFIG.video_targets.max_fps != 0.0 {
-        command
-            .arg("-r")
-            .arg(&CONFIG.video_targets.max_fps.to_string());
+    /// Pixel format and color tags, which for HDR sources depend on `hdr_mode`.
+    fn apply_color_arguments(&self, stream: &Stream, specifier: &str, command: &mut Command) {
+        let pix_fmt = &self.config.video_targets.pix_fmt;
+        if !stream.is_hdr() {
+            if !pix_fmt.is_empty() {
+                command.arg(format!("-pix_fmt:{}", specifier)).arg(pix_fmt);
+            }
+            return;
+        }
+
+        if self.config.video_targets.hdr_mode == "tonemap" {
+            if !pix_fmt.is_empty() {
+                command.arg(format!("-pix_fmt:{}", specifier)).arg(pix_fmt);
+            }
+            command
+                .arg(format!("-color_primaries:{}", specifier))
+                .arg("bt709")
+                .arg(format!("-color_trc:{}", specifier))
+                .arg("bt709")
+                .arg(format!("-colorspace:{}", specifier))
+                .arg("bt709");
+            return;
+        }
+
+        // HDR needs at least 10 bits per sample to survive the encode without banding
+        let pix_fmt = if pix_fmt.contains("10") || pix_fmt.contains("12") {
+            pix_fmt.as_str()
+        } else {
+            "yuv420p10le"
+        };
+        command.arg(format!("-pix_fmt:{}", specifier)).arg(pix_fmt);
+        for (option, value) in [
+            ("-color_primaries", &stream.color_primaries),
+            ("-color_trc", &stream.color_transfer),
+            ("-colorspace", &stream.color_space),
+        ] {
+            if let Some(value) = value {
+                command.arg(format!("{}:{}", option, specifier)).arg(value);
+            }
+        }
     }
 
-    if CONFIG.video_targets.max_bitrate != 0 {
-        command
-            .arg("-b:v")
-            .arg(&CONFIG.video_targets.max_bitrate.to_string());
+    fn apply_video_filters(&self, stream: &Stream, filters: &mut FilterChain) {
+        if self.config.filters.deinterlace {
+            filters.push(FilterNode::Deinterlace);
+        }
+
+        if self.config.filters.deblock > 0 {
+            filters.push(FilterNode::Deblock(self.config.filters.deblock));
+        }
+
+        if self.config.filters.denoise > 0 {
+            filters.push(FilterNode::Denoise(self.config.filters.denoise));
+        }
+
+        if self.config.video_targets.hdr_mode == "tonemap" && stream.is_hdr() {
+            filters.push(FilterNode::Tonemap(
+                self.config.video_targets.tonemap.clone(),
+            ));
+        }
     }
 
-    if CONFIG.video_targets.crf != -1 {
-        command
-            .arg("-crf")
-            .arg(&CONFIG.video_targets.crf.to_string());
+    fn handle_completed_file(&self, job: &Job) -> Result<(), String> {
+        match job.replacement {
+            Replacement::Always => self.replace_source(job),
+            Replacement::IfSmaller => {
+                let input_size = job.input.metadata().map_err(|e| e.to_string())?.len();
+                let output_size = std::fs::metadata(&job.temp_output)
+                    .map_err(|e| e.to_string())?
+                    .len();
+                if output_size < input_size {
+                    self.replace_source(job)
+                } else {
+                    match std::fs::remove_file(&job.temp_output) {
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(e.to_string()),
+                    }
+                }
+            }
+            Replacement::Never => {
+                self.check_output_free(job)?;
+                // Under output_directory several sources can map to the same output, so
+                // another name is picked rather than overwriting
+                let output = utils::unique_path(&job.output);
+                if output != job.output {
+                    println!(
+                        "{} already exists, writing {} instead",
+                        job.output.display(),
+                        output.display()
+                    );
+                }
+                if let Some(parent) = output.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| {
+                        format!(
+                            "Error creating output directory ({}): {}",
+                            parent.display(),
+                            e
+                        )
+                    })?;
+                }
+                utils::move_file(&job.temp_output, &output).map_err(|e| e.to_string())
+            }
+        }
     }
 
-    if !CONFIG.video_targets.ffmpeg_preset.is_empty() {
-        command
-            .arg("-preset")
-            .arg(&CONFIG.video_targets.ffmpeg_preset);
+    /// Refuses to write a kept source's output next to it when a file, such as the
+    /// source itself, already has its name.
+    fn check_output_free(&self, job: &Job) -> Result<(), String> {
+        let shares_directory = self.config.streamline.output_directory.is_empty();
+        if job.replacement == Replacement::Never
+            && (job.output == job.input || (shares_directory && job.output.exists()))
+        {
+            return Err(format!(
+                "File already exists and would be overwritten: {}",
+                job.output.display()
+            ));
+        }
+        Ok(())
     }
 
-    if !CONFIG.video_targets.pix_fmt.is_empty() {
-        command.arg("-pix_fmt").arg(&CONFIG.video_targets.pix_fmt);
+    /// Moves the output over the source, recycling the source first if enabled.
+    fn replace_source(&self, job: &Job) -> Result<(), String> {
+        let recycle_bin = RecycleBin::new(self.config);
+        let recycled = match recycle_bin.is_enabled() {
+            true => Some(recycle_bin.recycle(&job.input)?),
+            false => None,
+        };
+        if let Err(e) = utils::move_file(&job.temp_output, &job.input) {
+            if let Some(recycled) = recycled {
+                utils::move_file(&recycled, &job.input).map_err(|restore_error| {
+                    format!(
+                        "{} (original left at {}: {})",
+                        e,
+                        recycled.display(),
+                        restore_error
+                    )
+                })?;
+            }
+            return Err(e.to_string());
+        }
+        Ok(())
     }
 
-    if !CONFIG.video_targets.tune.is_empty() {
-        command.arg("-tune").arg(&CONFIG.video_targets.tune);
+    /// Plans and runs the ffmpeg job for a single file.
+    pub fn process_file(&self, input_file: &MediaFile) -> Result<(), String> {
+        let job = self.plan_file(input_file)?;
+        self.run_job(&job)
     }
 
-    if !CONFIG.video_targets.x265_params.is_empty() {
-        command
-            .arg("-x265-params")
-            .arg(&CONFIG.video_targets.x265_params);
+    /// Builds the job for `input_file`, using the profile matching its path.
+    pub fn plan_file(&self, input_file: &MediaFile) -> Result<Job, String> {
+        let config = self.config.for_file(&input_file.path)?;
+        let processor = Processor::new(&config);
+        let reasons = DecisionEngine::new(self.config).evaluate(input_file);
+        if let Some(reason) = reasons.iter().find(|reason| reason.skips_file()) {
+            return Err(format!("Skipped: {}", reason));
+        }
+        let mut job = processor.build_job(input_file)?;
+        job.profile = self
+            .config
+            .profile_for(&input_file.path)
+            .map(|profile| profile.name.clone());
+        job.reasons = reasons;
+        Ok(job)
     }
-}
 
-fn apply_video_filters(filters: &mut Vec<String>) {
-    if CONFIG.filters.deinterlace {
-        filters.push("yadif".to_string());
+    pub fn plan_files(&self, files: &[MediaFile]) -> Plan {
+        let mut plan = Plan::default();
+        for file in files {
+            match self.plan_file(file) {
+                Ok(job) => plan.jobs.push(job),
+                Err(e) => println!("Error planning file ({}): {}", file.path.display(), e),
+            }
+        }
+        plan
     }
 
-    if CONFIG.filters.deblock > 0 {
-        filters.push(format!("deblock={}", CONFIG.filters.deblock));
+    /// Runs a planned job, or prints it when `dry_run` is enabled.
+    pub fn run_job(&self, job: &Job) -> Result<(), String> {
+        let space = DiskSpace::new(self.config);
+        let space = self.space.unwrap_or(&space);
+        let prepared = self.prepare_job(job, space);
+        self.run_prepared_job(job, prepared)
     }
 
-    if CONFIG.filters.denoise > 0 {
-        filters.push(format!("hqdn3d={}", CONFIG.filters.denoise));
+    /// Reserves the disk space `job` needs, then stages its input.
+    fn prepare_job<'s>(
+        &self,
+        job: &Job,
+        space: &'s DiskSpace<'s>,
+    ) -> Result<Reservation<'s>, String> {
+        let reservation = space.reserve(job)?;
+        self.stage_input(job)?;
+        Ok(reservation)
     }
-}
 
-fn apply_audio_arguments(stream: &Stream, command: &mut Command, default_set: &mut bool) {
-    if !CONFIG.audio_targets.codec.is_empty()
-        && !CONFIG.audio_targets.codec.contains(&stream.codec_name)
-    {
-        command
-            .arg(format!("-c:a:{}", stream.index,))
-            .arg(&CONFIG.audio_targets.codec[0]);
+    /// Runs a job prepared by `prepare_job`, releasing its reserved space once done.
+    fn run_prepared_job(
+        &self,
+        job: &Job,
+        prepared: Result<Reservation<'_>, String>,
+    ) -> Result<(), String> {
+        if self.config.streamline.dry_run {
+            if let Some(first_pass) = job.first_pass_command() {
+                println!("Would run command: {:?}", first_pass);
+            }
+            println!("Would run command: {:?}", job.command());
+            return Ok(());
+        }
+
+        let result = prepared.and_then(|_reservation| self.execute_job(job));
+        self.remove_staged_input(job);
+        if let (Err(e), Some(journal)) = (&result, self.journal) {
+            journal.lock().unwrap().set_failed(&job.input, e)?;
+        }
+        result
     }
 
-    if !CONFIG.audio_targets.sample_rate.is_empty() {
-        let source_rate = stream.sample_rate;
-        if !CONFIG.audio_targets.sample_rate.contains(&source_rate) {
-            command
-                .arg(format!("-ar:{}", stream.index,))
-                .arg(&CONFIG.audio_targets.sample_rate[0].to_string());
+    fn record_state(
+        &self,
+        input: &Path,
+        state: JobState,
+        temp_output: Option<&Path>,
+    ) -> Result<(), String> {
+        match self.journal {
+            Some(journal) => journal.lock().unwrap().set_state(input, state, temp_output),
+            None => Ok(()),
+        }
+    }
+
+    /// Measures the encode against its source, refusing it below the configured minimums.
+    fn check_quality(&self, job: &Job) -> Result<(), String> {
+        let checker = QualityChecker::new(self.config);
+        // A copied video would only be compared with itself
+        if !checker.is_enabled() || job.streams.transcoded_streams("video").next().is_none() {
+            return Ok(());
+        }
+
+        let pb = self.create_encode_progress_bar(job, 0);
+        pb.set_message("Measuring quality");
+        let result = checker.measure(job.encode_input(), &job.temp_output, job.duration_secs);
+        pb.finish_and_clear();
+        if let Some(multi_progress) = self.progress {
+            multi_progress.remove(&pb);
+        }
+        let scores = result?;
+
+        if let Some(journal) = self.journal {
+            journal.lock().unwrap().set_quality(&job.input, &scores)?;
+        }
+        if self.config.streamline.debug {
+            println!("Quality of {}: {}", job.input.display(), scores);
         }
+        if let Err(e) = scores.check(self.config) {
+            return Err(format!(
+                "Quality check failed for {} ({}): {}",
+                job.input.display(),
+                scores,
+                e
+            ));
+        }
+        Ok(())
     }
 
-    if !CONFIG.audio_targets.language.is_empty()
-        && !stream.tags.language.is_empty()
-        && !CONFIG
-            .audio_targets
-            .language
-            .contains(&stream.tags.language)
-    {
-        command.arg("-map").arg(format!("-0:{}", stream.index));
+    /// A progress bar for `job` measured in milliseconds, or a spinner if `duration_ms` is 0.
+    fn create_encode_progress_bar(&self, job: &Job, duration_ms: u64) -> ProgressBar {
+        let name = job
+            .input
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let pb = utils::create_encode_progress_bar(duration_ms, &name);
+        let pb = match self.progress {
+            Some(multi_progress) => multi_progress.add(pb),
+            None => pb,
+        };
+        pb.enable_steady_tick(std::time::Duration::from_millis(500));
+        pb
     }
 
-    if !*default_set
-        && !CONFIG.audio_targets.default_language.is_empty()
-        && stream.tags.language == CONFIG.audio_targets.default_language
-    {
-        command
-            .arg(format!("-disposition:a:{}", stream.index))
-            .arg("default");
-        *default_set = true;
-    } else if stream.disposition.default == 1 {
+    fn load_journal(&self) -> Result<Option<Mutex<Journal>>, String> {
+        let journal_path = &self.config.streamline.journal;
+        if journal_path.is_empty() || self.config.streamline.dry_run {
+            return Ok(None);
+        }
+        let mut journal = Journal::load(Path::new(journal_path))?;
+        for orphan in journal.recover()? {
+            println!("Removed orphaned file: {}", orphan.display());
+        }
+        Ok(Some(Mutex::new(journal)))
+    }
+
+    fn base_command(&self, input: &Path) -> Command {
+        let mut command = Command::new(&self.config.ffmpeg.ffmpeg_path);
+        command.arg("-i").arg(input);
+        command.arg("-xerror");
+        command.arg("-progress").arg("pipe:1").arg("-nostats");
+        command.arg("-v").arg("error");
+
+        if self.config.get_job_threads() != 0 {
+            command
+                .arg("-threads")
+                .arg(self.config.get_job_threads().to_string());
+        }
         command
-            .arg(format!("-disposition:a:{}", stream.index))
-            .arg("0");
     }
-}
 
-fn apply_subtitle_arguments(stream: &Stream, command: &mut Command, default_set: &mut bool) {
-    if !CONFIG.subtitles.language.is_empty()
-        && !stream.tags.language.is_empty()
-        && !CONFIG.subtitles.language.contains(&stream.tags.language)
-    {
-        command.arg("-map").arg(format!("-0:s:{}?", stream.index));
+    fn apply_stream_arguments(
+        &self,
+        input_file: &MediaFile,
+        streams: &mut StreamPlan,
+        filters: &FilterGraph,
+        pass: Option<(u32, &Path)>,
+        command: &mut Command,
+    ) {
+        command.args(filters.args(streams, &input_file.info));
+        command.args(streams.args());
+        for planned in streams.transcoded_streams("video") {
+            let stream = input_file
+                .info
+                .streams
+                .iter()
+                .find(|stream| stream.index == planned.input_index);
+            if let (Some(stream), Some(specifier), Some(encoder)) =
+                (stream, planned.output_specifier(), &planned.codec)
+            {
+                self.apply_video_arguments(stream, encoder, &specifier, pass, command);
+            }
+        }
     }
 
-    if !*default_set
-        && !CONFIG.subtitles.default_language.is_empty()
-        && stream.tags.language == CONFIG.subtitles.default_language
-    {
-        command.arg("-disposition").arg("default");
-        *default_set = true;
-    } else if stream.disposition.default == 1 {
-        command.arg(format!("-disposition:s:{}", stream.index));
+    /// The video bitrate that brings `input_file` down to `target_size_mb`, after
+    /// accounting for the audio streams and the container overhead.
+    fn target_video_bitrate(
+        &self,
+        input_file: &MediaFile,
+        streams: &StreamPlan,
+    ) -> Result<u32, String> {
+        let duration: f64 = input_file.info.format.duration.parse().unwrap_or(0.0);
+        if duration <= 0.0 {
+            return Err("Cannot target a file size without a known duration".to_string());
+        }
+
+        let audio_bitrate: u64 = streams
+            .kept_streams()
+            .filter(|planned| planned.codec_type == "audio")
+            .map(|planned| {
+                let stream = input_file
+                    .info
+                    .streams
+                    .iter()
+                    .find(|stream| stream.index == planned.input_index);
+                match (planned.bitrate, stream) {
+                    (Some(bitrate), _) => bitrate as u64,
+                    (None, Some(stream)) if stream.bit_rate != 0 => stream.bit_rate as u64,
+                    // Containers like Matroska don't always report per-stream bitrates
+                    (None, stream) => {
+                        FALLBACK_CHANNEL_BITRATE
+                            * stream.and_then(|s| s.channels).unwrap_or(2) as u64
+                    }
+                }
+            })
+            .sum();
+
+        let target_bits = self.config.video_targets.target_size_mb as f64 * 8_000_000.0;
+        let total_bitrate = target_bits * (1.0 - CONTAINER_OVERHEAD) / duration;
+        let video_bitrate = total_bitrate - audio_bitrate as f64;
+        if video_bitrate < MIN_VIDEO_BITRATE as f64 {
+            return Err(format!(
+                "Target size of {} MB is too small for {:.0}s of video",
+                self.config.video_targets.target_size_mb, duration
+            ));
+        }
+
+        let mut video_bitrate = video_bitrate as u32;
+        if self.config.video_targets.max_bitrate != 0 {
+            video_bitrate = video_bitrate.min(self.config.video_targets.max_bitrate);
+        }
+        Ok(video_bitrate)
     }
-}
 
-fn handle_completed_file(input_file: &MediaFile, output_file: &str) -> Result<(), String> {
-    if CONFIG.streamline.always_replace {
-        match std::fs::rename(output_file, input_file.path.as_path()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
-        }
-    } else if CONFIG.streamline.replace_if_smaller {
-        let input_size = input_file.path.metadata().unwrap().len();
-        let output_size = std::fs::metadata(output_file).unwrap().len();
-        if output_size < input_size {
-            match std::fs::rename(output_file, input_file.path.as_path()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+    /// Where `input_file` is copied to when inputs are staged.
+    fn get_staged_input_path(&self, input_file: &Path) -> PathBuf {
+        let stem = input_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = input_file
+            .extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Path::new(&self.config.streamline.temp_directory).join(format!(
+            "{}-{:016x}.source.{}",
+            stem,
+            path_hash(input_file),
+            extension
+        ))
+    }
+
+    /// Where the pass logs of `input_file` are written: next to its temporary output,
+    /// unique per input path so concurrent jobs never share one.
+    fn get_passlog_path(&self, input_file: &Path, temp_output: &Path) -> PathBuf {
+        let stem = input_file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        directory_or_current(temp_output.parent()).join(format!(
+            "{}-{:016x}.passlog",
+            stem,
+            path_hash(input_file)
+        ))
+    }
+
+    fn build_job(&self, input_file: &MediaFile) -> Result<Job, String> {
+        let output = self.get_output_path(&input_file.path);
+        let temp_output = self.get_temp_output_path(&input_file.path, &output);
+        let staged_input = self
+            .config
+            .streamline
+            .stage_inputs
+            .then(|| self.get_staged_input_path(&input_file.path));
+        let encode_input = staged_input.as_deref().unwrap_or(&input_file.path);
+        let mut filters = FilterGraph::default();
+
+        let video_streams = input_file.info.get_streams_of_type("video");
+        if video_streams.is_empty() {
+            return Err("No video streams found!".to_string());
+        }
+        self.apply_video_filters(video_streams[0], &mut filters.video);
+        self.apply_scaling(video_streams[0], &mut filters.video);
+        filters
+            .video
+            .push_custom(&self.config.video_targets.filters);
+        filters
+            .audio
+            .push_custom(&self.config.audio_targets.filters);
+        filters.sort(&self.config.filters.order);
+
+        // Streams that already meet the targets are copied, only the rest are re-encoded
+        let mut streams = StreamPlan::new(self.config, &input_file.info, !filters.video.is_empty());
+
+        let transcoded_video = streams.transcoded_streams("video").count() as u32;
+        let mut two_pass = self.config.video_targets.two_pass && transcoded_video > 0;
+        // Files already under the target size keep the usual CRF or max_bitrate encode
+        let over_target_size = DecisionEngine::new(self.config)
+            .check_file_size(&input_file.info)
+            .is_some();
+        if over_target_size && transcoded_video > 0 {
+            let bitrate = self.target_video_bitrate(input_file, &streams)? / transcoded_video;
+            for planned in streams.streams.iter_mut().filter(|planned| {
+                planned.codec_type == "video" && planned.action == StreamAction::Transcode
+            }) {
+                // Never spend more bits than the source had
+                let source_bitrate = input_file
+                    .info
+                    .streams
+                    .iter()
+                    .find(|stream| stream.index == planned.input_index)
+                    .map(|stream| stream.bit_rate)
+                    .unwrap_or(0);
+                planned.bitrate = Some(match source_bitrate {
+                    0 => bitrate,
+                    source_bitrate => bitrate.min(source_bitrate),
+                });
             }
+            two_pass = true;
+        }
+        // Two passes only help when encoding to a bitrate
+        two_pass = two_pass
+            && streams
+                .transcoded_streams("video")
+                .all(|planned| planned.bitrate.is_some());
+        let passlog = two_pass.then(|| self.get_passlog_path(&input_file.path, &temp_output));
+
+        let mut command = self.base_command(encode_input);
+        command.arg("-f").arg(&self.config.streamline.output_format);
+        let pass = passlog.as_deref().map(|passlog| (2, passlog));
+        self.apply_stream_arguments(input_file, &mut streams, &filters, pass, &mut command);
+        command.arg(&temp_output);
+
+        // The first pass only analyses the video, so everything else is left out
+        let first_pass_args = passlog.as_deref().map(|passlog| {
+            let mut first_pass = self.base_command(encode_input);
+            self.apply_stream_arguments(
+                input_file,
+                &mut streams.video_only(),
+                &filters,
+                Some((1, passlog)),
+                &mut first_pass,
+            );
+            first_pass.arg("-an").arg("-sn").arg("-dn");
+            first_pass.arg("-f").arg("null").arg("-");
+            first_pass
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        });
+
+        let replacement = if self.config.streamline.always_replace {
+            Replacement::Always
+        } else if self.config.streamline.replace_if_smaller {
+            Replacement::IfSmaller
         } else {
-            match std::fs::remove_file(output_file) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.to_string()),
+            Replacement::Never
+        };
+        Ok(Job {
+            input: input_file.path.clone(),
+            staged_input,
+            profile: None,
+            reasons: Vec::new(),
+            program: command.get_program().to_string_lossy().into_owned(),
+            args: command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            temp_output,
+            output,
+            replacement,
+            duration_secs: input_file.info.format.duration.parse().unwrap_or(0.0),
+            streams,
+            first_pass_args,
+            passlog,
+        })
+    }
+
+    /// Runs `job`, making sure no partial or rejected output is left behind when it fails.
+    fn execute_job(&self, job: &Job) -> Result<(), String> {
+        let result = self.encode_and_replace(job);
+        if result.is_err() && job.temp_output.exists() {
+            if let Err(e) = std::fs::remove_file(&job.temp_output) {
+                println!(
+                    "Error removing temporary output ({}): {}",
+                    job.temp_output.display(),
+                    e
+                );
             }
         }
-    } else {
-        let desired_name = output_file
-            .strip_suffix(&CONFIG.streamline.temporary_suffix)
-            .unwrap();
-        if Path::new(desired_name).exists() {
-            return Err(format!(
-                "File already exists and would be overwritten: {}",
-                desired_name
+        result
+    }
+
+    fn encode_and_replace(&self, job: &Job) -> Result<(), String> {
+        self.check_output_free(job)?;
+        if let Some(parent) = job.temp_output.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                format!(
+                    "Error creating temporary directory ({}): {}",
+                    parent.display(),
+                    e
+                )
+            })?;
+        }
+        if job.temp_output.exists() {
+            std::fs::remove_file(&job.temp_output).map_err(|e| e.to_string())?;
+        }
+
+        let mut command = job.command();
+        self.record_state(&job.input, JobState::Encoding, Some(&job.temp_output))?;
+        let encode_pb = self.create_encode_progress_bar(job, (job.duration_secs * 1000.0) as u64);
+        let result = match job.first_pass_command() {
+            Some(mut first_pass) => {
+                let prefix = encode_pb.prefix();
+                encode_pb.set_prefix(format!("{} (pass 1/2)", prefix));
+                let first_result =
+                    progress::run_with_progress(&mut first_pass, job.duration_secs, &encode_pb);
+                encode_pb.set_prefix(format!("{} (pass 2/2)", prefix));
+                encode_pb.set_position(0);
+                match first_result {
+                    Ok(cmd_out) if !cmd_out.status.success() => Ok(cmd_out),
+                    Ok(_) => {
+                        progress::run_with_progress(&mut command, job.duration_secs, &encode_pb)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            None => progress::run_with_progress(&mut command, job.duration_secs, &encode_pb),
+        };
+        encode_pb.finish_and_clear();
+        if let Some(multi_progress) = self.progress {
+            multi_progress.remove(&encode_pb);
+        }
+        if let Some(passlog) = &job.passlog {
+            // Leftover logs are harmless, so they never fail a finished encode
+            if let Err(e) = remove_passlogs(passlog) {
+                println!(
+                    "Warning: failed to remove pass logs ({}): {}",
+                    passlog.display(),
+                    e
+                );
+            }
+        }
+        let cmd_out =
+            result.map_err(|e| format!("Error running ffmpeg: {} -- {:?}", e, command))?;
+        if !cmd_out.status.success() {
+            return Err(self.reject_output(
+                job,
+                format!(
+                    "Error running ffmpeg: {} -- {:?}",
+                    String::from_utf8_lossy(&cmd_out.stderr),
+                    command
+                ),
             ));
         }
-        match std::fs::rename(output_file, desired_name) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
+        if self.config.streamline.debug {
+            println!(
+                "CMD: {:?}\nOutput: {}",
+                command,
+                String::from_utf8_lossy(&cmd_out.stderr)
+            );
+        }
+
+        self.record_state(&job.input, JobState::Verifying, Some(&job.temp_output))?;
+        let verifier = Verifier::new(self.config);
+        if verifier.is_enabled() {
+            if let Err(e) = verifier.verify(job) {
+                return Err(self.reject_output(
+                    job,
+                    format!("Verification failed for {}: {}", job.input.display(), e),
+                ));
+            }
+        }
+        if let Err(e) = self.check_quality(job) {
+            return Err(self.reject_output(job, e));
         }
+        self.handle_completed_file(job)?;
+
+        self.record_state(&job.input, JobState::Replaced, None)
     }
-}
 
-pub fn process_file(input_file: &MediaFile) -> Result<(), String> {
-    let output_file = get_output_path(&input_file.path);
+    /// Moves the output of a failed job into the quarantine directory, or deletes it
+    /// if none is configured, leaving the source untouched. Returns `reason` along
+    /// with any error moving the output.
+    fn reject_output(&self, job: &Job, reason: String) -> String {
+        if !job.temp_output.exists() {
+            return reason;
+        }
+        let quarantine_directory = &self.config.verification.quarantine_directory;
+        if quarantine_directory.is_empty() {
+            return match std::fs::remove_file(&job.temp_output) {
+                Ok(_) => reason,
+                Err(e) => format!("{} (failed to remove output: {})", reason, e),
+            };
+        }
 
-    if Path::new(&output_file).exists() {
-        std::fs::remove_file(&output_file).unwrap();
+        match quarantine(
+            &job.temp_output,
+            &job.output,
+            Path::new(quarantine_directory),
+        ) {
+            Ok(path) => format!("{} (output quarantined at {})", reason, path.display()),
+            Err(e) => format!("{} (failed to quarantine output: {})", reason, e),
+        }
     }
 
-    let mut command = Command::new(&CONFIG.ffmpeg.ffmpeg_path);
-    let mut filters = Vec::new();
+    pub fn process_files(&self, to_process: Vec<MediaFile>) {
+        let plan = self.plan_files(&to_process);
+        self.apply_plan(plan);
+    }
 
-    command.arg("-i").arg(input_file.path.as_path());
-    command.arg("-xerror");
-    command.arg("-v").arg("error");
-    command.arg("-f").arg(&CONFIG.streamline.output_format);
+    /// Runs every job in `plan` on the worker pool, skipping inputs the journal
+    /// shows were already replaced.
+    pub fn apply_plan(&self, plan: Plan) {
+        let journal = match self.load_journal() {
+            Ok(journal) => journal,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+        let multi_progress = MultiProgress::new();
+        let space = DiskSpace::new(self.config);
+        let processor = Processor {
+            config: self.config,
+            journal: journal.as_ref(),
+            progress: Some(&multi_progress),
+            space: Some(&space),
+        };
+        let jobs: Vec<Job> = match &journal {
+            Some(journal) => {
+                let journal = journal.lock().unwrap();
+                plan.jobs
+                    .into_iter()
+                    .filter(|job| {
+                        let replaced = journal.is_replaced(&job.input);
+                        if replaced {
+                            println!("Skipping already processed file: {}", job.input.display());
+                        }
+                        !replaced
+                    })
+                    .collect()
+            }
+            None => plan.jobs,
+        };
 
-    if CONFIG.get_threads() != 0 {
-        command
-            .arg("-threads")
-            .arg(&CONFIG.get_threads().to_string());
+        let processing_pb =
+            multi_progress.add(utils::create_progress_bar(jobs.len() as u64, true, 500));
+
+        processing_pb.set_message("Processing files...");
+        if let Some(journal) = &journal {
+            let inputs: Vec<PathBuf> = jobs.iter().map(|job| job.input.clone()).collect();
+            if let Err(e) = journal.lock().unwrap().set_queued(&inputs) {
+                println!("{}", e);
+                return;
+            }
+        }
+
+        let shared_pb = Arc::new(Mutex::new(processing_pb));
+        // Jobs are handed to the workers as each becomes free, so the next job is prepared
+        // while the current ones encode
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        thread::scope(|scope| {
+            let processor = &processor;
+            let space = &space;
+            scope.spawn(move || processor.prepare_jobs(jobs, space, sender));
+            for _ in 0..self.config.ffmpeg.encode_workers.max(1) {
+                let receiver = receiver.clone();
+                let prg = shared_pb.clone();
+                scope.spawn(move || {
+                    processor.process_job_worker(receiver, prg);
+                });
+            }
+        });
+
+        utils::set_pb_finish_message(
+            &shared_pb.lock().unwrap(),
+            "✅ Files processed!".to_string(),
+        );
+
+        // Keep the recycle directory within its retention limits as it grows
+        if !self.config.streamline.dry_run {
+            if let Err(e) = RecycleBin::new(self.config).purge(false) {
+                println!("{}", e);
+            }
+        }
     }
 
-    let video_streams = input_file.info.get_streams_of_type("video");
-    let audio_streams = input_file.info.get_streams_of_type("audio");
-    let subtitle_streams = input_file.info.get_streams_of_type("subtitle");
+    /// Sends each job to the workers once it is prepared, so disk space is reserved and
+    /// inputs are staged in order.
+    fn prepare_jobs(
+        &self,
+        jobs: Vec<Job>,
+        space: &'a DiskSpace<'a>,
+        sender: SyncSender<PreparedJob<'a>>,
+    ) {
+        for job in jobs {
+            let prepared = self.prepare_job(&job, space);
+            if sender.send((job, prepared)).is_err() {
+                break;
+            }
+        }
+    }
 
-    if video_streams.is_empty() {
-        return Err("No video streams found!".to_string());
+    /// Copies the input of `job` to its staged path, if it has one.
+    fn stage_input(&self, job: &Job) -> Result<(), String> {
+        let staged_input = match &job.staged_input {
+            Some(staged_input) if !self.config.streamline.dry_run => staged_input,
+            _ => return Ok(()),
+        };
+        if let Some(journal) = self.journal {
+            journal
+                .lock()
+                .unwrap()
+                .set_staged_input(&job.input, staged_input)?;
+        }
+        let pb = self.create_encode_progress_bar(job, 0);
+        pb.set_message("Staging");
+        let result = utils::copy_verified(&job.input, staged_input);
+        pb.finish_and_clear();
+        if let Some(multi_progress) = self.progress {
+            multi_progress.remove(&pb);
+        }
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(staged_input);
+            return Err(format!(
+                "Error staging {} to {}: {}",
+                job.input.display(),
+                staged_input.display(),
+                e
+            ));
+        }
+        Ok(())
     }
-    apply_video_arguments(video_streams[0], &mut command);
-    apply_video_filters(&mut filters);
-    apply_aspect_ratio_corrections(video_streams[0], &mut filters);
 
-    let default_audio = input_file.info.get_default_stream_of_type("audio");
-    let mut audio_default_set = false;
-    if default_audio.is_some()
-        && !CONFIG.audio_targets.default_language.is_empty()
-        && default_audio.unwrap().tags.language == CONFIG.audio_targets.default_language
-    {
-        audio_default_set = true;
+    fn remove_staged_input(&self, job: &Job) {
+        if let Some(staged_input) = &job.staged_input {
+            if staged_input.exists() {
+                if let Err(e) = std::fs::remove_file(staged_input) {
+                    println!(
+                        "Error removing staged input ({}): {}",
+                        staged_input.display(),
+                        e
+                    );
+                }
+            }
+        }
     }
 
-    for stream in audio_streams {
-        apply_audio_arguments(stream, &mut command, &mut audio_default_set);
+    fn process_job_worker(
+        &self,
+        jobs: Arc<Mutex<Receiver<PreparedJob<'a>>>>,
+        pb: Arc<Mutex<ProgressBar>>,
+    ) {
+        loop {
+            let (job, prepared) = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+
+            {
+                let pb = pb.lock().unwrap();
+                pb.set_message(format!("Processing: {}", job.input.display()));
+                pb.tick();
+            }
+            match self.run_prepared_job(&job, prepared) {
+                Ok(_) => pb.lock().unwrap().inc(1),
+                Err(e) => {
+                    println!("Error processing file: {}", e)
+                }
+            }
+        }
+    }
+}
+
+/// `directory`, or the current directory when it is missing or empty, as it is for
+/// the parent of a bare file name.
+fn directory_or_current(directory: Option<&Path>) -> &Path {
+    match directory {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    }
+}
+
+fn path_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Rounds down to an even dimension, as required by chroma subsampled pixel formats.
+fn even(dimension: u32) -> u32 {
+    (dimension & !1).max(2)
+}
+
+/// x265 parameters that carry the HDR10 color description and static metadata of
+/// `stream` into the encode.
+fn hdr10_x265_params(stream: &Stream) -> Vec<String> {
+    let mut params = vec!["hdr10=1".to_string(), "repeat-headers=1".to_string()];
+    for (param, value) in [
+        ("colorprim", &stream.color_primaries),
+        ("transfer", &stream.color_transfer),
+        ("colormatrix", &stream.color_space),
+    ] {
+        if let Some(value) = value {
+            params.push(format!("{}={}", param, value));
+        }
     }
 
-    if !subtitle_streams.is_empty() {
-        let mut subtitle_default_set = false;
-        let default_subtitle = input_file.info.get_default_stream_of_type("subtitle");
-        if default_subtitle.is_some()
-            && !CONFIG.subtitles.default_language.is_empty()
-            && default_subtitle.unwrap().tags.language == CONFIG.subtitles.default_language
+    // x265 expects chromaticities in units of 0.00002 and luminance in 0.0001 cd/m²
+    if let Some(display) = stream.mastering_display() {
+        let point =
+            |(x, y): (f64, f64)| format!("({},{})", (x * 50000.0).round(), (y * 50000.0).round());
+        params.push(format!(
+            "master-display=G{}B{}R{}WP{}L({},{})",
+            point(display.green),
+            point(display.blue),
+            point(display.red),
+            point(display.white_point),
+            (display.max_luminance * 10000.0).round(),
+            (display.min_luminance * 10000.0).round()
+        ));
+    }
+    if let Some(light_level) = stream.content_light_level() {
+        params.push(format!(
+            "max-cll={},{}",
+            light_level.max_content, light_level.max_average
+        ));
+    }
+    params
+}
+
+/// Quotes `value` for `-x265-params`, which ffmpeg splits on `:` and `=` and where a
+/// backslash escapes the next character, as in Windows paths. Quotes are ended, escaped
+/// and reopened around any `'` in the value.
+fn x265_param_value(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Moves `temp_output` into `directory`, named after `output`.
+fn quarantine(temp_output: &Path, output: &Path, directory: &Path) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(directory)?;
+    let name = output.file_name().unwrap_or(temp_output.as_os_str());
+    let destination = utils::unique_path(&directory.join(name));
+    utils::move_file(temp_output, &destination)?;
+    Ok(destination)
+}
+
+/// Removes every log file written by a two-pass encode using `passlog` as its prefix.
+fn remove_passlogs(passlog: &Path) -> std::io::Result<()> {
+    let prefix = match passlog.file_name() {
+        Some(prefix) => prefix.to_string_lossy(),
+        None => return Ok(()),
+    };
+    for entry in std::fs::read_dir(directory_or_current(passlog.parent()))? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(prefix.as_ref())
         {
-            subtitle_default_set = true;
+            std::fs::remove_file(entry.path())?;
         }
-        for stream in subtitle_streams {
-            apply_subtitle_arguments(stream, &mut command, &mut subtitle_default_set);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::media::{FFProbeOutput, Format, SideData};
+
+    fn video_stream(width: u32, height: u32) -> Stream {
+        Stream {
+            index: 0,
+            codec_type: "video".to_string(),
+            codec_name: "h264".to_string(),
+            width: Some(width),
+            height: Some(height),
+            ..Default::default()
         }
     }
 
-    let user_video_filters = CONFIG.video_targets.filters.clone();
-    let user_audio_filters = CONFIG.audio_targets.filters.clone();
+    fn scale(config: &Config, stream: &Stream) -> Vec<FilterNode> {
+        let mut filters = FilterChain::default();
+        Processor::new(config).apply_scaling(stream, &mut filters);
+        filters.nodes().to_vec()
+    }
+
+    #[test]
+    fn scaling_fits_within_targets() {
+        let mut config = Config::for_tests();
+        config.video_targets.max_width = 1920;
+        config.video_targets.max_height = 1080;
 
-    if !user_video_filters.is_empty() {
-        filters.push(
-            user_video_filters
-                .split(',')
-                .collect::<Vec<&str>>()
-                .join(","),
+        assert_eq!(
+            scale(&config, &video_stream(3840, 1600)),
+            [FilterNode::Scale {
+                width: 1920,
+                height: 800,
+                algorithm: "bicubic".to_string()
+            }]
         );
+        assert!(scale(&config, &video_stream(1920, 1080)).is_empty());
     }
-    if !user_audio_filters.is_empty() {
-        filters.push(
-            user_audio_filters
-                .split(',')
-                .collect::<Vec<&str>>()
-                .join(","),
+
+    #[test]
+    fn scaling_squares_anamorphic_pixels() {
+        let mut config = Config::for_tests();
+        config.video_targets.max_width = 640;
+        config.video_targets.letterbox = true;
+        let stream = Stream {
+            sample_aspect_ratio: Some("32:27".to_string()),
+            ..video_stream(720, 480)
+        };
+
+        // Only one limit is set, so there is nothing to letterbox to
+        assert_eq!(
+            scale(&config, &stream),
+            [FilterNode::Scale {
+                width: 640,
+                height: 360,
+                algorithm: "bicubic".to_string()
+            }]
         );
     }
 
-    if !filters.is_empty() {
-        command.arg("-vf").arg(filters.join(","));
-    }
-    command.arg(&output_file);
+    #[test]
+    fn scaling_letterboxes_to_both_limits() {
+        let mut config = Config::for_tests();
+        config.video_targets.max_width = 1280;
+        config.video_targets.max_height = 720;
+        config.video_targets.letterbox = true;
 
-    if CONFIG.streamline.dry_run {
-        println!("Would run command: {:?}", command);
-        Ok(())
-    } else {
-        match command.output() {
-            Ok(cmd_out) => {
-                handle_completed_file(input_file, &output_file)?;
-                if !cmd_out.status.success() {
-                    return Err(format!(
-                        "Error running ffmpeg: {} -- {:?}",
-                        String::from_utf8_lossy(&cmd_out.stderr),
-                        command
-                    ));
-                }
-                if CONFIG.streamline.debug {
-                    println!(
-                        "CMD: {:?}\nOutput: {}",
-                        command,
-                        String::from_utf8_lossy(&cmd_out.stdout)
-                    );
+        assert_eq!(
+            scale(&config, &video_stream(1440, 1080)),
+            [
+                FilterNode::Scale {
+                    width: 960,
+                    height: 720,
+                    algorithm: "bicubic".to_string()
+                },
+                FilterNode::Letterbox {
+                    width: 1280,
+                    height: 720
                 }
+            ]
+        );
+    }
 
-                Ok(())
-            }
-            Err(e) => Err(format!("Error running ffmpeg: {} -- {:?}", e, command)),
+    fn media_file(duration: &str, audio_bit_rate: u32) -> MediaFile {
+        let audio = Stream {
+            index: 1,
+            codec_type: "audio".to_string(),
+            codec_name: "aac".to_string(),
+            bit_rate: audio_bit_rate,
+            channels: Some(6),
+            ..Default::default()
+        };
+        MediaFile {
+            path: PathBuf::from("movie.mkv"),
+            info: FFProbeOutput {
+                streams: vec![video_stream(1920, 1080), audio],
+                format: Format {
+                    duration: duration.to_string(),
+                    size: "0".to_string(),
+                    ..Default::default()
+                },
+            },
         }
     }
+
+    fn target_bitrate(config: &Config, file: &MediaFile) -> Result<u32, String> {
+        let streams = StreamPlan::new(config, &file.info, false);
+        Processor::new(config).target_video_bitrate(file, &streams)
+    }
+
+    #[test]
+    fn target_bitrate_leaves_room_for_audio() {
+        let mut config = Config::for_tests();
+        config.video_targets.target_size_mb = 100;
+
+        // 800 Mbit over 100s, less the container overhead and the audio
+        let file = media_file("100", 128_000);
+        assert_eq!(target_bitrate(&config, &file), Ok(7_712_000));
+
+        // Without a reported bitrate, audio is estimated per channel
+        let file = media_file("100", 0);
+        assert_eq!(
+            target_bitrate(&config, &file),
+            Ok(7_840_000 - 6 * FALLBACK_CHANNEL_BITRATE as u32)
+        );
+
+        config.video_targets.max_bitrate = 5_000_000;
+        assert_eq!(target_bitrate(&config, &file), Ok(5_000_000));
+    }
+
+    #[test]
+    fn target_bitrate_rejects_impossible_targets() {
+        let mut config = Config::for_tests();
+        config.video_targets.target_size_mb = 1;
+
+        assert!(target_bitrate(&config, &media_file("3600", 128_000)).is_err());
+        assert!(target_bitrate(&config, &media_file("", 128_000)).is_err());
+    }
+
+    #[test]
+    fn target_size_only_applies_over_it() {
+        let mut config = Config::for_tests();
+        config.video_targets.target_size_mb = 100;
+        let mut file = media_file("100", 128_000);
+        file.info.streams[0].codec_name = "mpeg4".to_string();
+        file.info.streams[0].bit_rate = 5_000_000;
+
+        file.info.format.size = "90000000".to_string();
+        let job = Processor::new(&config).build_job(&file).unwrap();
+        assert_eq!(job.streams.streams[0].action, StreamAction::Transcode);
+        assert_eq!(job.streams.streams[0].bitrate, None);
+        assert!(job.passlog.is_none());
+
+        // Capped at the source bitrate, below the 7.7 Mbit/s the size allows
+        file.info.format.size = "200000000".to_string();
+        let job = Processor::new(&config).build_job(&file).unwrap();
+        assert_eq!(job.streams.streams[0].bitrate, Some(5_000_000));
+        assert!(job.passlog.is_some());
+    }
+
+    #[test]
+    fn hdr10_params_carry_metadata() {
+        let rational = |value: &str| Some(value.to_string());
+        let stream = Stream {
+            color_primaries: Some("bt2020".to_string()),
+            color_transfer: Some("smpte2084".to_string()),
+            color_space: Some("bt2020nc".to_string()),
+            side_data_list: vec![
+                SideData {
+                    side_data_type: "Mastering display metadata".to_string(),
+                    red_x: rational("34000/50000"),
+                    red_y: rational("16000/50000"),
+                    green_x: rational("13250/50000"),
+                    green_y: rational("34500/50000"),
+                    blue_x: rational("7500/50000"),
+                    blue_y: rational("3000/50000"),
+                    white_point_x: rational("15635/50000"),
+                    white_point_y: rational("16450/50000"),
+                    min_luminance: rational("50/10000"),
+                    max_luminance: rational("10000000/10000"),
+                    ..Default::default()
+                },
+                SideData {
+                    side_data_type: "Content light level metadata".to_string(),
+                    max_content: Some(1000),
+                    max_average: Some(400),
+                    ..Default::default()
+                },
+            ],
+            ..video_stream(3840, 2160)
+        };
+
+        assert_eq!(
+            hdr10_x265_params(&stream),
+            [
+                "hdr10=1",
+                "repeat-headers=1",
+                "colorprim=bt2020",
+                "transfer=smpte2084",
+                "colormatrix=bt2020nc",
+                "master-display=G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)L(10000000,50)",
+                "max-cll=1000,400",
+            ]
+        );
+    }
+
+    #[test]
+    fn hdr10_params_without_metadata() {
+        assert_eq!(
+            hdr10_x265_params(&video_stream(3840, 2160)),
+            ["hdr10=1", "repeat-headers=1"]
+        );
+    }
+
+    #[test]
+    fn x265_param_values_are_quoted() {
+        assert_eq!(
+            x265_param_value(r"Z:\Temp\movie-0123.passlog"),
+            r"'Z:\Temp\movie-0123.passlog'"
+        );
+        assert_eq!(x265_param_value("/tmp/it's:here"), r"'/tmp/it'\''s:here'");
+    }
 }
@@ -4,106 +4,170 @@ use std::process::Command;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::models::config::CONFIG;
+use crate::models::config::Config;
 use crate::models::file::MediaFile;
 use crate::models::media::FFProbeOutput;
+use crate::probe_cache::ProbeCache;
 use crate::utils;
-use std::fs::DirEntry;
-
-pub fn check_ffprobe() -> Result<(), String> {
-    match Command::new(&CONFIG.ffmpeg.ffprobe_path)
-        .arg("-version")
-        .output()
-    {
-        Ok(_) => Ok(()),
-        Err(_) => Err("FFprobe not found!".to_string()),
-    }
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Runs ffprobe against media files to collect their stream information.
+pub struct Prober<'a> {
+    config: &'a Config,
+    refresh_cache: bool,
 }
 
-pub fn call_ffprobe(file: &DirEntry) -> Result<String, String> {
-    let output = Command::new(&CONFIG.ffmpeg.ffprobe_path)
-        .arg("-v")
-        .arg("quiet")
-        .arg("-show_format")
-        .arg("-show_streams")
-        .arg("-show_entries")
-        .arg("stream_tags:format_tags")
-        .arg("-print_format")
-        .arg("json")
-        .arg(&file.path())
-        .output()
-        .map_err(|e| e.to_string())?;
-
-    if output.status.success() {
-        let output = String::from_utf8(output.stdout).map_err(|e| e.to_string())?;
-        Ok(output)
-    } else {
-        let error = String::from_utf8(output.stderr).map_err(|e| e.to_string())?;
-        Err(error)
+impl<'a> Prober<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Prober {
+            config,
+            refresh_cache: false,
+        }
     }
-}
 
-pub fn bulk_get_file_info(files: Vec<DirEntry>) -> Vec<MediaFile> {
-    let allowed_workers = CONFIG.ffmpeg.ffprobe_workers;
+    /// Ignore cached results and probe every file again, updating the cache.
+    pub fn refresh_cache(mut self, refresh_cache: bool) -> Self {
+        self.refresh_cache = refresh_cache;
+        self
+    }
+
+    pub fn check_ffprobe(&self) -> Result<(), String> {
+        match Command::new(&self.config.ffmpeg.ffprobe_path)
+            .arg("-version")
+            .output()
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err("FFprobe not found!".to_string()),
+        }
+    }
+
+    pub fn call_ffprobe(&self, path: &Path) -> Result<String, String> {
+        let output = Command::new(&self.config.ffmpeg.ffprobe_path)
+            .arg("-v")
+            .arg("quiet")
+            .arg("-show_format")
+            .arg("-show_streams")
+            .arg("-show_entries")
+            .arg("stream_tags:format_tags")
+            .arg("-print_format")
+            .arg("json")
+            .arg(path)
+            .output()
+            .map_err(|e| e.to_string())?;
+
+        if output.status.success() {
+            let output = String::from_utf8(output.stdout).map_err(|e| e.to_string())?;
+            Ok(output)
+        } else {
+            let error = String::from_utf8(output.stderr).map_err(|e| e.to_string())?;
+            Err(error)
+        }
+    }
+
+    pub fn bulk_get_file_info(&self, files: Vec<PathBuf>) -> Vec<MediaFile> {
+        let allowed_workers = self.config.ffmpeg.ffprobe_workers;
+
+        let files = Arc::new(Mutex::new(VecDeque::from(files)));
+        let pb = utils::create_progress_bar(files.lock().unwrap().len() as u64, true, 500);
+        pb.set_message("Probing files...");
+        let shared_pb = Arc::new(Mutex::new(pb));
 
-    let files = Arc::new(Mutex::new(VecDeque::from(files)));
-    let pb = utils::create_progress_bar(files.lock().unwrap().len() as u64, true, 500);
-    pb.set_message("Probing files...");
-    let shared_pb = Arc::new(Mutex::new(pb));
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let cache = self.load_cache().map(|cache| Arc::new(Mutex::new(cache)));
 
-    let results = Arc::new(Mutex::new(Vec::new()));
-    let mut workers = vec![];
+        thread::scope(|scope| {
+            for _ in 0..allowed_workers {
+                let files = files.clone();
+                let results = results.clone();
+                let prg = shared_pb.clone();
+                let cache = cache.clone();
+                scope.spawn(move || {
+                    self.probe_file_worker(files, results, prg, cache);
+                });
+            }
+        });
+
+        if let Some(cache) = cache {
+            let mut cache = cache.lock().unwrap();
+            cache.prune();
+            if let Err(e) = cache.save() {
+                println!("{}", e);
+            }
+        }
 
-    for _ in 0..allowed_workers {
-        let files = files.clone();
-        let results = results.clone();
-        let prg = shared_pb.clone();
-        workers.push(thread::spawn(move || {
-            probe_file_worker(files, results, prg);
-        }));
+        let output = results.lock().unwrap().drain(..).flatten().collect();
+        utils::set_pb_finish_message(&shared_pb.lock().unwrap(), "✅ Files probed!".to_string());
+        output
     }
 
-    for worker in workers {
-        worker.join().unwrap();
+    fn probe_file_worker(
+        &self,
+        files: Arc<Mutex<VecDeque<PathBuf>>>,
+        results: Arc<Mutex<Vec<Result<MediaFile, String>>>>,
+        pb: Arc<Mutex<ProgressBar>>,
+        cache: Option<Arc<Mutex<ProbeCache>>>,
+    ) {
+        loop {
+            let file = match files.lock().unwrap().pop_front() {
+                Some(file) => file,
+                None => break,
+            };
+
+            let info = match self.get_cached_file_info(&file, cache.as_deref()) {
+                Ok(info) => info,
+                Err(e) => {
+                    results.lock().unwrap().push(Err(e));
+                    pb.lock().unwrap().inc(1);
+                    continue;
+                }
+            };
+
+            results
+                .lock()
+                .unwrap()
+                .push(Ok(MediaFile { path: file, info }));
+            pb.lock().unwrap().inc(1);
+        }
     }
 
-    let output = results.lock().unwrap().drain(..).flatten().collect();
-    utils::set_pb_finish_message(&shared_pb.lock().unwrap(), "✅ Files probed!".to_string());
-    output
-}
+    fn load_cache(&self) -> Option<ProbeCache> {
+        let cache_path = &self.config.streamline.probe_cache;
+        if cache_path.is_empty() {
+            return None;
+        }
+        Some(ProbeCache::load(Path::new(cache_path)))
+    }
 
-fn probe_file_worker(
-    files: Arc<Mutex<VecDeque<DirEntry>>>,
-    results: Arc<Mutex<Vec<Result<MediaFile, String>>>>,
-    pb: Arc<Mutex<ProgressBar>>,
-) {
-    loop {
-        let file = match files.lock().unwrap().pop_front() {
-            Some(file) => file,
-            None => break,
+    fn get_cached_file_info(
+        &self,
+        path: &Path,
+        cache: Option<&Mutex<ProbeCache>>,
+    ) -> Result<FFProbeOutput, String> {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return self.get_file_info(path),
         };
+        let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
 
-        let info = match get_file_info(&file) {
-            Ok(info) => info,
-            Err(e) => {
-                results.lock().unwrap().push(Err(e));
-                pb.lock().unwrap().inc(1);
-                continue;
+        if !self.refresh_cache {
+            if let Some(output) = cache.lock().unwrap().get(path, &metadata) {
+                return parse_ffprobe_output(output);
             }
-        };
+        }
 
-        results.lock().unwrap().push(Ok(MediaFile {
-            path: file.path(),
-            info,
-        }));
-        pb.lock().unwrap().inc(1);
+        let output = self.call_ffprobe(path)?;
+        let info = parse_ffprobe_output(&output)?;
+        cache.lock().unwrap().insert(path, &metadata, output);
+        Ok(info)
     }
-}
 
-pub fn get_file_info(file: &DirEntry) -> Result<FFProbeOutput, String> {
-    parse_ffprobe_output(&call_ffprobe(file)?)
+    pub fn get_file_info(&self, path: &Path) -> Result<FFProbeOutput, String> {
+        parse_ffprobe_output(&self.call_ffprobe(path)?)
+    }
 }
-fn parse_ffprobe_output(output: &str) -> Result<FFProbeOutput, String> {
+
+pub fn parse_ffprobe_output(output: &str) -> Result<FFProbeOutput, String> {
     serde_json::from_str(output)
         .map_err(|e| format!("Error parsing ffprobe output: {}\nOutput: {}", e, output))
 }
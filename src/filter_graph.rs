@@ -0,0 +1,295 @@
+use crate::models::media::{FFProbeOutput, Stream};
+use crate::stream_plan::{StreamAction, StreamPlan};
+
+/// The default position of each kind of filter in a chain, used when `filters.order`
+/// does not mention it.
+pub const DEFAULT_ORDER: [&str; 6] = [
+    "deinterlace",
+    "deblock",
+    "denoise",
+    "tonemap",
+    "scale",
+    "custom",
+];
+
+/// A single filter in a chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterNode {
+    Deinterlace,
+    Deblock(u32),
+    Denoise(u32),
+    /// Converts HDR to SDR BT.709
+    Tonemap(String),
+    Scale {
+        width: u32,
+        height: u32,
+        algorithm: String,
+    },
+    /// Centers the frame on a black canvas of the given size
+    Letterbox {
+        width: u32,
+        height: u32,
+    },
+    /// A user supplied filter, which may reference probed stream properties with
+    /// placeholders such as `{width}`
+    Custom(String),
+}
+
+impl FilterNode {
+    /// The name used to order the node with `filters.order`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FilterNode::Deinterlace => "deinterlace",
+            FilterNode::Deblock(_) => "deblock",
+            FilterNode::Denoise(_) => "denoise",
+            FilterNode::Tonemap(_) => "tonemap",
+            FilterNode::Scale { .. } | FilterNode::Letterbox { .. } => "scale",
+            FilterNode::Custom(_) => "custom",
+        }
+    }
+
+    pub fn render(&self, stream: &Stream) -> String {
+        match self {
+            FilterNode::Deinterlace => "yadif".to_string(),
+            FilterNode::Deblock(strength) => format!("deblock={}", strength),
+            FilterNode::Denoise(strength) => format!("hqdn3d={}", strength),
+            FilterNode::Tonemap(algorithm) => format!(
+                "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,\
+                 tonemap=tonemap={}:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p",
+                algorithm
+            ),
+            FilterNode::Scale {
+                width,
+                height,
+                algorithm,
+            } => format!("scale={}:{}:flags={},setsar=1", width, height, algorithm),
+            FilterNode::Letterbox { width, height } => {
+                format!("pad={}:{}:(ow-iw)/2:(oh-ih)/2", width, height)
+            }
+            FilterNode::Custom(filter) => substitute_placeholders(filter, stream),
+        }
+    }
+}
+
+/// Replaces `{width}`, `{height}`, `{fps}`, `{sample_rate}` and `{channels}` with the
+/// probed values of `stream`.
+fn substitute_placeholders(filter: &str, stream: &Stream) -> String {
+    filter
+        .replace("{width}", &stream.width.unwrap_or(0).to_string())
+        .replace("{height}", &stream.height.unwrap_or(0).to_string())
+        .replace("{fps}", &stream.avg_frame_rate.to_string())
+        .replace("{sample_rate}", &stream.sample_rate.to_string())
+        .replace("{channels}", &stream.channels.unwrap_or(0).to_string())
+}
+
+/// An ordered list of filters applied to a single stream.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    nodes: Vec<FilterNode>,
+}
+
+impl FilterChain {
+    pub fn push(&mut self, node: FilterNode) {
+        self.nodes.push(node);
+    }
+
+    /// Adds a user supplied, comma separated chain of filters.
+    pub fn push_custom(&mut self, filters: &str) {
+        if !filters.is_empty() {
+            self.push(FilterNode::Custom(filters.to_string()));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn nodes(&self) -> &[FilterNode] {
+        &self.nodes
+    }
+
+    /// Sorts the nodes by the position of their kind in `order`. Kinds missing from
+    /// `order` follow the listed ones, in their default order.
+    pub fn sort(&mut self, order: &[String]) {
+        self.nodes.sort_by_key(
+            |node| match order.iter().position(|kind| kind == node.kind()) {
+                Some(position) => position,
+                None => {
+                    order.len()
+                        + DEFAULT_ORDER
+                            .iter()
+                            .position(|kind| *kind == node.kind())
+                            .unwrap_or(DEFAULT_ORDER.len())
+                }
+            },
+        );
+    }
+
+    pub fn render(&self, stream: &Stream) -> String {
+        self.nodes
+            .iter()
+            .map(|node| node.render(stream))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+/// Whether a rendered chain uses labels or several chains, and so has to be passed
+/// with `-filter_complex` rather than as a simple per-stream filter.
+fn is_complex(rendered: &str) -> bool {
+    rendered.contains(';') || rendered.contains('[')
+}
+
+/// The video and audio filter chains for a file, kept apart so each ends up on
+/// streams of its own type.
+#[derive(Debug, Clone, Default)]
+pub struct FilterGraph {
+    pub video: FilterChain,
+    pub audio: FilterChain,
+}
+
+impl FilterGraph {
+    pub fn sort(&mut self, order: &[String]) {
+        self.video.sort(order);
+        self.audio.sort(order);
+    }
+
+    /// The filter arguments for every transcoded stream in `streams`. Chains that need
+    /// `-filter_complex` are given an output label, which `streams` then maps in place
+    /// of the input stream.
+    pub fn args(&self, streams: &mut StreamPlan, info: &FFProbeOutput) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut complex = Vec::new();
+
+        for planned in streams.streams.iter_mut() {
+            let chain = match planned.codec_type.as_str() {
+                "video" => &self.video,
+                "audio" => &self.audio,
+                _ => continue,
+            };
+            if chain.is_empty() || planned.action != StreamAction::Transcode {
+                continue;
+            }
+            let (stream, specifier) = match (
+                info.streams
+                    .iter()
+                    .find(|stream| stream.index == planned.input_index),
+                planned.output_specifier(),
+            ) {
+                (Some(stream), Some(specifier)) => (stream, specifier),
+                _ => continue,
+            };
+
+            let rendered = chain.render(stream);
+            if is_complex(&rendered) {
+                let label = format!("[{}]", specifier.replace(':', ""));
+                complex.push(format!("[0:{}]{}{}", planned.input_index, rendered, label));
+                planned.map_label = Some(label);
+            } else {
+                args.push(format!("-filter:{}", specifier));
+                args.push(rendered);
+            }
+        }
+
+        if !complex.is_empty() {
+            args.push("-filter_complex".to_string());
+            args.push(complex.join(";"));
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::config::Config;
+    use crate::models::media::{Format, Tags};
+
+    fn stream(index: u32, codec_type: &str, codec: &str) -> Stream {
+        Stream {
+            index,
+            codec_type: codec_type.to_string(),
+            codec_name: codec.to_string(),
+            width: Some(1920),
+            height: Some(1080),
+            tags: Tags {
+                language: "eng".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn info() -> FFProbeOutput {
+        FFProbeOutput {
+            streams: vec![
+                stream(0, "video", "h264"),
+                stream(1, "audio", "dts"),
+                stream(2, "audio", "aac"),
+            ],
+            format: Format::default(),
+        }
+    }
+
+    fn kinds(chain: &FilterChain) -> Vec<&'static str> {
+        chain.nodes().iter().map(FilterNode::kind).collect()
+    }
+
+    #[test]
+    fn sorts_by_order_then_default_order() {
+        let mut chain = FilterChain::default();
+        chain.push_custom("eq=gamma=1.2");
+        chain.push(FilterNode::Scale {
+            width: 1280,
+            height: 720,
+            algorithm: "bicubic".to_string(),
+        });
+        chain.push(FilterNode::Denoise(4));
+        chain.push(FilterNode::Deinterlace);
+
+        chain.sort(&[]);
+        assert_eq!(kinds(&chain), ["deinterlace", "denoise", "scale", "custom"]);
+
+        chain.sort(&["custom".to_string(), "scale".to_string()]);
+        assert_eq!(kinds(&chain), ["custom", "scale", "deinterlace", "denoise"]);
+    }
+
+    #[test]
+    fn simple_chains_filter_transcoded_streams() {
+        let info = info();
+        let mut streams = StreamPlan::new(&Config::for_tests(), &info, true);
+        let mut graph = FilterGraph::default();
+        graph.video.push(FilterNode::Deinterlace);
+        graph.video.push_custom("crop={width}:{height}-280");
+        graph.audio.push_custom("loudnorm");
+
+        // The copied aac stream is left unfiltered
+        assert_eq!(
+            graph.args(&mut streams, &info),
+            [
+                "-filter:v:0",
+                "yadif,crop=1920:1080-280",
+                "-filter:a:0",
+                "loudnorm"
+            ]
+        );
+        assert!(streams
+            .streams
+            .iter()
+            .all(|stream| stream.map_label.is_none()));
+    }
+
+    #[test]
+    fn labelled_chains_use_filter_complex() {
+        let info = info();
+        let mut streams = StreamPlan::new(&Config::for_tests(), &info, true);
+        let mut graph = FilterGraph::default();
+        graph.video.push_custom("split[a][b];[a][b]hstack");
+
+        assert_eq!(
+            graph.args(&mut streams, &info),
+            ["-filter_complex", "[0:0]split[a][b];[a][b]hstack[v0]"]
+        );
+        assert_eq!(streams.streams[0].map_label.as_deref(), Some("[v0]"));
+    }
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::quality::QualityScores;
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Encoding,
+    Verifying,
+    Replaced,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobEntry {
+    pub state: JobState,
+    #[serde(default)]
+    pub temp_output: Option<PathBuf>,
+    /// Local copy of the source, while inputs are staged
+    #[serde(default)]
+    pub staged_input: Option<PathBuf>,
+    /// Size and mtime of the file at `path` once the job was replaced
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified_secs: u64,
+    #[serde(default)]
+    pub modified_nanos: u32,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub quality: Option<QualityScores>,
+}
+
+/// Durable record of each file's processing state, saved after every transition so an
+/// interrupted run can be resumed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(skip)]
+    journal_path: PathBuf,
+    jobs: HashMap<PathBuf, JobEntry>,
+}
+
+impl Journal {
+    /// Loads the journal at `journal_path`, starting empty if it does not exist yet.
+    pub fn load(journal_path: &Path) -> Result<Journal, String> {
+        let mut journal = match fs::read_to_string(journal_path) {
+            Ok(contents) => serde_json::from_str::<Journal>(&contents).map_err(|e| {
+                format!("Error parsing journal ({}): {}", journal_path.display(), e)
+            })?,
+            Err(_) => Journal::default(),
+        };
+        journal.journal_path = journal_path.to_path_buf();
+        Ok(journal)
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&JobEntry> {
+        self.jobs.get(path)
+    }
+
+    /// Cleans up after jobs that were interrupted mid-encode, returning them to the queue.
+    /// Returns the orphaned temp outputs and staged inputs that were removed.
+    pub fn recover(&mut self) -> Result<Vec<PathBuf>, String> {
+        let mut removed = Vec::new();
+        for job in self.jobs.values_mut() {
+            // Staged inputs are removed once their job ends, so any left over are orphans
+            if let Some(staged_input) = job.staged_input.take() {
+                remove_orphan(staged_input, &mut removed)?;
+            }
+            if job.state != JobState::Encoding && job.state != JobState::Verifying {
+                continue;
+            }
+            if let Some(temp_output) = job.temp_output.take() {
+                remove_orphan(temp_output, &mut removed)?;
+            }
+            job.state = JobState::Queued;
+        }
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Whether `path` was already replaced and has not changed since.
+    pub fn is_replaced(&self, path: &Path) -> bool {
+        let job = match self.jobs.get(path) {
+            Some(job) if job.state == JobState::Replaced => job,
+            _ => return false,
+        };
+        match path.metadata() {
+            Ok(metadata) => {
+                job.size == metadata.len()
+                    && (job.modified_secs, job.modified_nanos)
+                        == utils::get_modified_time(&metadata)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn entry(&mut self, path: &Path, state: JobState) -> &mut JobEntry {
+        let job = self
+            .jobs
+            .entry(path.to_path_buf())
+            .or_insert_with(|| JobEntry {
+                state,
+                temp_output: None,
+                staged_input: None,
+                size: 0,
+                modified_secs: 0,
+                modified_nanos: 0,
+                error: None,
+                quality: None,
+            });
+        job.state = state;
+        job
+    }
+
+    pub fn set_state(
+        &mut self,
+        path: &Path,
+        state: JobState,
+        temp_output: Option<&Path>,
+    ) -> Result<(), String> {
+        let job = self.entry(path, state);
+        job.temp_output = temp_output.map(Path::to_path_buf);
+        job.error = None;
+
+        if state == JobState::Replaced {
+            if let Ok(metadata) = path.metadata() {
+                job.size = metadata.len();
+                (job.modified_secs, job.modified_nanos) = utils::get_modified_time(&metadata);
+            }
+        }
+        self.save()
+    }
+
+    /// Queues every path in `paths`, saving the journal once for the whole batch.
+    pub fn set_queued(&mut self, paths: &[PathBuf]) -> Result<(), String> {
+        for path in paths {
+            let job = self.entry(path, JobState::Queued);
+            job.temp_output = None;
+            job.error = None;
+        }
+        self.save()
+    }
+
+    /// Records that the input of `path` is being copied to `staged_input`.
+    pub fn set_staged_input(&mut self, path: &Path, staged_input: &Path) -> Result<(), String> {
+        if let Some(job) = self.jobs.get_mut(path) {
+            job.staged_input = Some(staged_input.to_path_buf());
+        }
+        self.save()
+    }
+
+    pub fn set_quality(&mut self, path: &Path, scores: &QualityScores) -> Result<(), String> {
+        if let Some(job) = self.jobs.get_mut(path) {
+            job.quality = Some(scores.clone());
+        }
+        self.save()
+    }
+
+    pub fn set_failed(&mut self, path: &Path, error: &str) -> Result<(), String> {
+        let job = self.entry(path, JobState::Failed);
+        job.temp_output = None;
+        job.error = Some(error.to_string());
+        self.save()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        let temp_path = self.journal_path.with_extension("tmp");
+        File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .map_err(|e| format!("Error writing journal ({}): {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, &self.journal_path).map_err(|e| {
+            format!(
+                "Error writing journal ({}): {}",
+                self.journal_path.display(),
+                e
+            )
+        })
+    }
+}
+
+fn remove_orphan(path: PathBuf, removed: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    fs::remove_file(&path)
+        .map_err(|e| format!("Error removing orphaned file ({}): {}", path.display(), e))?;
+    removed.push(path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("streamline-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn recover_requeues_interrupted_jobs() {
+        let directory = test_directory("journal-recover");
+        let input = directory.join("a.mkv");
+        let temp_output = directory.join("a_temp.mkv");
+        let staged_input = directory.join("a.source.mkv");
+        for path in [&input, &temp_output, &staged_input] {
+            fs::write(path, b"data").unwrap();
+        }
+
+        let journal_path = directory.join("journal.json");
+        let mut journal = Journal::load(&journal_path).unwrap();
+        journal.set_queued(std::slice::from_ref(&input)).unwrap();
+        journal.set_staged_input(&input, &staged_input).unwrap();
+        journal
+            .set_state(&input, JobState::Encoding, Some(&temp_output))
+            .unwrap();
+
+        let mut journal = Journal::load(&journal_path).unwrap();
+        let mut removed = journal.recover().unwrap();
+        removed.sort();
+        assert_eq!(removed, [staged_input.clone(), temp_output.clone()]);
+        assert!(input.exists() && !temp_output.exists() && !staged_input.exists());
+
+        let journal = Journal::load(&journal_path).unwrap();
+        let job = journal.get(&input).unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        assert!(job.temp_output.is_none() && job.staged_input.is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn replaced_until_changed() {
+        let directory = test_directory("journal-replaced");
+        let input = directory.join("a.mkv");
+        fs::write(&input, b"encoded").unwrap();
+
+        let mut journal = Journal::load(&directory.join("journal.json")).unwrap();
+        assert!(!journal.is_replaced(&input));
+        journal.set_state(&input, JobState::Replaced, None).unwrap();
+        assert!(journal.is_replaced(&input));
+
+        fs::write(&input, b"replaced by something else").unwrap();
+        assert!(!journal.is_replaced(&input));
+        journal.set_failed(&input, "failed").unwrap();
+        assert!(!journal.is_replaced(&input));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
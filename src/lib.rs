@@ -0,0 +1,32 @@
+//! Streamline analyzes media libraries with ffprobe and transcodes the files that
+//! fall outside the configured targets with ffmpeg.
+//!
+//! Each stage of a run is exposed as its own type, all of which borrow a [`Config`]:
+//! [`Scanner`] finds media files, [`Prober`] collects their stream information,
+//! [`DecisionEngine`] picks the files that need processing and [`Processor`]
+//! runs ffmpeg against them.
+
+pub mod decision;
+pub mod discovery;
+pub mod disk_space;
+pub mod ffmpeg;
+pub mod ffprobe;
+pub mod filter_graph;
+pub mod journal;
+pub mod models;
+pub mod plan;
+pub mod probe_cache;
+pub mod progress;
+pub mod quality;
+pub mod recycle;
+pub mod stream_plan;
+pub mod utils;
+pub mod verify;
+pub mod watch;
+
+pub use decision::DecisionEngine;
+pub use discovery::Scanner;
+pub use ffmpeg::Processor;
+pub use ffprobe::Prober;
+pub use models::config::Config;
+pub use models::file::MediaFile;
@@ -1,169 +1,239 @@
 use std::fs;
-use std::fs::DirEntry;
-use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use indicatif::ProgressBar;
+use clap::Parser;
 
-use crate::models::file::MediaFile;
-use models::config::CONFIG;
+use crate::cli::{Cli, Commands, ExplainFormat};
+use streamline::decision::Explanation;
+use streamline::plan::Plan;
+use streamline::recycle::RecycleBin;
+use streamline::watch::LibraryWatcher;
+use streamline::{utils, Config, DecisionEngine, MediaFile, Prober, Processor, Scanner};
 
-mod ffmpeg;
-mod ffprobe;
-mod models;
-mod utils;
+mod cli;
 
 fn main() {
-    let extensions = &CONFIG.streamline.file_extensions;
-    let path = Path::new(&CONFIG.streamline.source_directory);
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Commands::Process);
 
-    sanity_check(path);
-    CONFIG.display();
-
-    let collection_spinner = utils::create_spinner(false);
-    let files = collect_files_with_extensions(
-        path,
-        extensions,
-        CONFIG.streamline.recursive,
-        0,
-        CONFIG.streamline.max_depth,
-        &collection_spinner,
-    )
-    .unwrap();
-    collection_spinner.finish_with_message(format!("✅ Files collected! Found: {}", files.len()));
-
-    let to_process = check_files(files);
-
-    let processing_pb = utils::create_progress_bar(to_process.len() as u64, true, 500);
+    let mut config = load_config(&cli.config);
+    cli.overrides.apply(&mut config);
+    if let Commands::Plan { .. } = command {
+        config.streamline.dry_run = true;
+    }
 
-    processing_pb.set_message("Processing files...");
-    for file in to_process {
-        processing_pb.set_message(format!("Processing: {}", file.path.as_path().display()));
-        processing_pb.tick();
-        match ffmpeg::process_file(&file) {
-            Ok(_) => processing_pb.inc(1),
-            Err(e) => {
-                println!("Error processing file: {}", e)
+    match command {
+        Commands::Config => config.display(),
+        Commands::Purge { all } => {
+            sanity_check(&config);
+            let recycle_bin = RecycleBin::new(&config);
+            if !recycle_bin.is_enabled() {
+                println!("No recycle_directory is configured");
+                std::process::exit(1);
+            }
+            match recycle_bin.purge(all) {
+                Ok(summary) if config.streamline.dry_run => println!(
+                    "Would remove {} recycled files, freeing {} MB",
+                    summary.removed.len(),
+                    summary.freed_bytes / 1_000_000
+                ),
+                Ok(summary) => println!(
+                    "✅ Removed {} recycled files, freeing {} MB",
+                    summary.removed.len(),
+                    summary.freed_bytes / 1_000_000
+                ),
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Scan => {
+            for file in collect_files(&config, true) {
+                println!("{}", file.display());
+            }
+        }
+        Commands::Probe => {
+            let prober = Prober::new(&config).refresh_cache(cli.refresh_probe);
+            for file in prober.bulk_get_file_info(collect_files(&config, true)) {
+                print_file_info(&file);
+            }
+        }
+        Commands::Explain { format, all } => {
+            let files = Prober::new(&config)
+                .refresh_cache(cli.refresh_probe)
+                .bulk_get_file_info(collect_files(
+                    &config,
+                    matches!(format, ExplainFormat::Text),
+                ));
+            let decision_engine = DecisionEngine::new(&config);
+            let explanations: Vec<Explanation> = files
+                .iter()
+                .map(|file| decision_engine.explain(file))
+                .filter(|explanation| all || !explanation.reasons.is_empty())
+                .collect();
+            print_explanations(&explanations, format);
+        }
+        Commands::Plan { output } => {
+            let files = Prober::new(&config)
+                .refresh_cache(cli.refresh_probe)
+                .bulk_get_file_info(collect_files(&config, true));
+            let to_process = DecisionEngine::new(&config).check_files(files);
+            let processor = Processor::new(&config);
+            match output {
+                Some(output) => {
+                    let plan = processor.plan_files(&to_process);
+                    match plan.save(&output) {
+                        Ok(_) => println!(
+                            "✅ Plan with {} jobs written to {}",
+                            plan.jobs.len(),
+                            output.display()
+                        ),
+                        Err(e) => {
+                            println!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                None => processor.process_files(to_process),
+            }
+        }
+        Commands::Apply { plan } => {
+            let plan = match Plan::load(&plan) {
+                Ok(plan) => plan,
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            sanity_check(&config);
+            Processor::new(&config).apply_plan(plan);
+        }
+        Commands::Process => {
+            let files = Prober::new(&config)
+                .refresh_cache(cli.refresh_probe)
+                .bulk_get_file_info(collect_files(&config, true));
+            let to_process = DecisionEngine::new(&config).check_files(files);
+            Processor::new(&config).process_files(to_process);
+        }
+        Commands::Watch => {
+            sanity_check(&config);
+            config.display();
+            let watcher = LibraryWatcher::new(&config).refresh_probe(cli.refresh_probe);
+            if let Err(e) = watcher.run() {
+                println!("{}", e);
+                std::process::exit(1);
             }
         }
     }
-    utils::set_pb_finish_message(&processing_pb, "✅ Files processed!".to_string());
 }
 
-fn debug_print_ln(s: &str) {
-    if CONFIG.streamline.debug {
-        println!("{}", s);
+fn load_config(config_path: &Path) -> Config {
+    let spinner = utils::create_spinner(false);
+    match Config::load(config_path) {
+        Ok(config) => {
+            spinner.finish_with_message("✅ Config loaded!");
+            config
+        }
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn _check_file(file: MediaFile, to_process: &mut Vec<MediaFile>) {
-    if CONFIG.video_targets.force_filter || CONFIG.audio_targets.force_filter {
-        debug_print_ln("Force filter enabled - processing all files");
-        to_process.push(file);
-        return;
+fn collect_files(config: &Config, display_config: bool) -> Vec<PathBuf> {
+    sanity_check(config);
+    if display_config {
+        config.display();
     }
 
-    let video_streams = file
-        .info
-        .streams
-        .iter()
-        .filter(|s| s.codec_type == "video")
-        .collect::<Vec<_>>();
-    let audio_streams = file
-        .info
-        .streams
-        .iter()
-        .filter(|s| s.codec_type == "audio")
-        .collect::<Vec<_>>();
-
-    for stream in video_streams {
-        if !CONFIG.video_targets.codec.is_empty()
-            && !CONFIG.video_targets.codec.contains(&stream.codec_name)
-        {
-            debug_print_ln(&format!("Codec: {} not in target list", stream.codec_name));
-            to_process.push(file);
-            return;
-        }
+    Scanner::new(config).collect_files().unwrap()
+}
 
-        if (CONFIG.video_targets.max_height != 0
-            && stream.height.unwrap() > CONFIG.video_targets.max_height)
-            || (CONFIG.video_targets.max_width != 0
-                && stream.width.unwrap() > CONFIG.video_targets.max_width)
-        {
-            debug_print_ln(&format!(
-                "Resolution {}x{} exceeds target {}x{}",
-                stream.width.unwrap(),
-                stream.height.unwrap(),
-                CONFIG.video_targets.max_width,
-                CONFIG.video_targets.max_height
+fn print_file_info(file: &MediaFile) {
+    println!(
+        "{} ({}, {}s)",
+        file.path.display(),
+        file.info.format.format_name,
+        file.info.format.duration
+    );
+    for stream in &file.info.streams {
+        let mut details = format!(
+            "     #{} {}: {}",
+            stream.index, stream.codec_type, stream.codec_name
+        );
+        if let (Some(width), Some(height)) = (stream.width, stream.height) {
+            details.push_str(&format!(
+                " {}x{} @ {:.3}fps",
+                width, height, stream.avg_frame_rate
             ));
-            to_process.push(file);
-            return;
         }
-
-        if CONFIG.video_targets.max_fps != 0.0
-            && stream.avg_frame_rate > CONFIG.video_targets.max_fps
-        {
-            debug_print_ln(&format!(
-                "FPS: {} exceeds target {}",
-                stream.avg_frame_rate, CONFIG.video_targets.max_fps
-            ));
-            to_process.push(file);
-            return;
+        if stream.sample_rate != 0 {
+            details.push_str(&format!(" {}Hz", stream.sample_rate));
         }
-
-        if CONFIG.video_targets.max_bitrate != 0
-            && stream.bit_rate > CONFIG.video_targets.max_bitrate
-        {
-            debug_print_ln(&format!(
-                "Bitrate: {} exceeds target {}",
-                stream.bit_rate, CONFIG.video_targets.max_bitrate
-            ));
-            to_process.push(file);
-            return;
+        if !stream.tags.language.is_empty() {
+            details.push_str(&format!(" [{}]", stream.tags.language));
         }
+        println!("{}", details);
     }
+}
 
-    for stream in audio_streams {
-        if !CONFIG.audio_targets.codec.is_empty()
-            && !CONFIG.audio_targets.codec.contains(&stream.codec_name)
-        {
-            debug_print_ln(&format!("Codec: {} not in target list", stream.codec_name));
-            to_process.push(file);
-            return;
+fn print_explanations(explanations: &[Explanation], format: ExplainFormat) {
+    match format {
+        ExplainFormat::Text => {
+            for explanation in explanations {
+                match &explanation.profile {
+                    Some(profile) => {
+                        println!("{} (profile: {})", explanation.path.display(), profile)
+                    }
+                    None => println!("{}", explanation.path.display()),
+                }
+                if explanation.reasons.is_empty() {
+                    println!("     Meets all targets");
+                }
+                for reason in &explanation.reasons {
+                    println!("     - {}", reason);
+                }
+            }
+        }
+        ExplainFormat::Json => match serde_json::to_string_pretty(explanations) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("Error serializing explanations: {}", e),
+        },
+        ExplainFormat::Csv => {
+            println!("path,profile,rule,stream,reason");
+            for explanation in explanations {
+                let path = explanation.path.to_string_lossy();
+                let profile = explanation.profile.clone().unwrap_or_default();
+                if explanation.reasons.is_empty() {
+                    println!("{},{},,,", csv_field(&path), csv_field(&profile));
+                }
+                for reason in &explanation.reasons {
+                    println!(
+                        "{},{},{},{},{}",
+                        csv_field(&path),
+                        csv_field(&profile),
+                        reason.rule(),
+                        reason.stream().map(|s| s.to_string()).unwrap_or_default(),
+                        csv_field(&reason.to_string())
+                    );
+                }
+            }
         }
     }
 }
 
-fn check_files(files: Vec<DirEntry>) -> Vec<MediaFile> {
-    let mut needs_processing = Vec::new();
-
-    let total_files = files.len();
-
-    let processed_files: Vec<MediaFile> = ffprobe::bulk_get_file_info(files);
-
-    let pb = utils::create_progress_bar(processed_files.len() as u64, true, 500);
-    pb.set_message("Analyzing files...");
-    for file in processed_files {
-        pb.inc(1);
-        pb.tick();
-
-        _check_file(file, &mut needs_processing);
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
     }
-
-    utils::set_pb_finish_message(
-        &pb,
-        format!(
-            "✅ Files Analyzed! Found: {}/{} that need processing",
-            needs_processing.len(),
-            total_files
-        ),
-    );
-
-    needs_processing
 }
 
-fn sanity_check(path: &Path) {
+fn sanity_check(config: &Config) {
+    let path = Path::new(&config.streamline.source_directory);
     let spinner = utils::create_spinner(true);
     spinner.set_message("Sanity checking".to_string());
 
@@ -181,7 +251,7 @@ fn sanity_check(path: &Path) {
         }
     }
 
-    match ffmpeg::check_ffmpeg() {
+    match Processor::new(config).check_ffmpeg() {
         Ok(_) => spinner.tick(),
         Err(e) => {
             println!("{}", e);
@@ -189,7 +259,7 @@ fn sanity_check(path: &Path) {
         }
     }
 
-    match ffprobe::check_ffprobe() {
+    match Prober::new(config).check_ffprobe() {
         Ok(_) => spinner.tick(),
         Err(e) => {
             println!("{}", e);
@@ -197,7 +267,7 @@ fn sanity_check(path: &Path) {
         }
     }
 
-    failed = CONFIG.sanity_check() || failed;
+    failed = config.sanity_check() || failed;
 
     if failed {
         println!("❌ Sanity check failed!");
@@ -205,52 +275,3 @@ fn sanity_check(path: &Path) {
     }
     spinner.finish_with_message("✅ Sanity check passed!");
 }
-
-fn collect_files_with_extensions(
-    path: &Path,
-    extensions: &Vec<String>,
-    recursive: bool,
-    depth: u32,
-    max_depth: u32,
-    spinner: &ProgressBar,
-) -> io::Result<Vec<DirEntry>> {
-    let mut files: Vec<DirEntry> = Vec::new();
-
-    if !CONFIG.streamline.exclude_directories.is_empty() {
-        for dir in &CONFIG.streamline.exclude_directories {
-            if path.ends_with(dir) {
-                return Ok(files);
-            }
-        }
-    }
-
-    if depth <= max_depth {
-        let objs = fs::read_dir(path)?;
-        spinner.set_message(format!("Collecting files... Searching {}", path.display()));
-
-        for obj in objs {
-            let obj = obj?;
-            let path = obj.path();
-            spinner.tick();
-
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if extensions.contains(&ext.to_string_lossy().into_owned()) {
-                        files.push(obj);
-                    }
-                }
-            } else if recursive && path.is_dir() {
-                let sub_files = collect_files_with_extensions(
-                    &path,
-                    extensions,
-                    recursive,
-                    depth + 1,
-                    max_depth,
-                    spinner,
-                )?;
-                files.extend(sub_files);
-            }
-        }
-    }
-    Ok(files)
-}
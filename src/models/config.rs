@@ -1,36 +1,13 @@
-use crate::utils;
-use once_cell::sync::Lazy;
-use serde::Deserialize;
+use crate::filter_graph;
+use crate::models::profile::Profile;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::thread::available_parallelism;
 
-pub static CONFIG: Lazy<Config> = Lazy::new(load_config);
-
-fn load_config() -> Config {
-    let spinner = utils::create_spinner(false);
-    let config_path = Path::new("config.toml");
-    let config = match fs::read_to_string(config_path) {
-        Ok(config) => config,
-        Err(e) => {
-            println!("Error reading config file: {}", e);
-            std::process::exit(1);
-        }
-    };
-
-    match toml::from_str(&config) {
-        Ok(config) => {
-            spinner.finish_with_message("✅ Config loaded!");
-            config
-        }
-        Err(e) => {
-            println!("Error parsing config file: {}", e);
-            std::process::exit(1);
-        }
-    }
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub streamline: StreamLine,
     pub ffmpeg: FFmpeg,
@@ -38,9 +15,38 @@ pub struct Config {
     pub audio_targets: AudioTargets,
     pub subtitles: Subtitles,
     pub filters: Filters,
+    #[serde(default)]
+    pub dolby_vision: DolbyVision,
+    #[serde(default)]
+    pub quality: Quality,
+    #[serde(default)]
+    pub verification: Verification,
+    #[serde(default)]
+    pub watch: Watch,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+impl FromStr for Config {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| format!("Error parsing config file: {}", e))
+    }
 }
 
 impl Config {
+    pub fn load(config_path: &Path) -> Result<Config, String> {
+        let config = fs::read_to_string(config_path).map_err(|e| {
+            format!(
+                "Error reading config file ({}): {}",
+                config_path.display(),
+                e
+            )
+        })?;
+        config.parse()
+    }
+
     pub fn sanity_check(&self) -> bool {
         let mut failed: bool = false;
         if self.streamline.always_replace {
@@ -64,6 +70,37 @@ impl Config {
             failed = true;
         }
 
+        if self.streamline.stage_inputs && self.streamline.temp_directory.is_empty() {
+            println!("Error: stage_inputs requires a temp_directory to copy inputs to");
+            failed = true;
+        }
+
+        // Purging deletes from the recycle directory, which must never reach the library
+        let recycle_directory = &self.streamline.recycle_directory;
+        if !recycle_directory.is_empty() {
+            for (name, directory) in [
+                ("source_directory", &self.streamline.source_directory),
+                ("output_directory", &self.streamline.output_directory),
+            ] {
+                if !directory.is_empty() && paths_overlap(recycle_directory, directory) {
+                    println!(
+                        "Error: recycle_directory cannot be, contain or be inside {}",
+                        name
+                    );
+                    failed = true;
+                }
+            }
+        }
+
+        if !LOW_SPACE_ACTIONS.contains(&self.streamline.low_space_action.as_str()) {
+            println!(
+                "Error: unknown low_space_action: {} (expected one of: {})",
+                self.streamline.low_space_action,
+                LOW_SPACE_ACTIONS.join(", ")
+            );
+            failed = true;
+        }
+
         if !Path::new(&self.streamline.temp_directory).exists() {
             fs::create_dir_all(&self.streamline.temp_directory).unwrap();
         }
@@ -83,9 +120,153 @@ impl Config {
             }
         }
 
+        let target_errors = self.target_errors();
+        for error in &target_errors {
+            println!("Error: {}", error);
+            failed = true;
+        }
+
+        for (profile, policy) in [
+            (5, &self.dolby_vision.profile_5),
+            (7, &self.dolby_vision.profile_7),
+            (8, &self.dolby_vision.profile_8),
+        ] {
+            if !DOLBY_VISION_POLICIES.contains(&policy.as_str()) {
+                println!(
+                    "Error: dolby_vision.profile_{} must be one of: {}",
+                    profile,
+                    DOLBY_VISION_POLICIES.join(", ")
+                );
+                failed = true;
+            }
+        }
+        if self.dolby_vision.profile_5 == "strip" {
+            println!("Error: dolby_vision.profile_5 cannot be strip, as profile 5 has no HDR10 base layer");
+            failed = true;
+        }
+
+        for metric in &self.quality.metrics {
+            if !QUALITY_METRICS.contains(&metric.as_str()) {
+                println!(
+                    "Error: unknown quality metric: {} (expected one of: {})",
+                    metric,
+                    QUALITY_METRICS.join(", ")
+                );
+                failed = true;
+            }
+        }
+        for (metric, threshold) in [
+            ("vmaf", self.quality.min_vmaf),
+            ("ssim", self.quality.min_ssim),
+            ("psnr", self.quality.min_psnr),
+        ] {
+            if threshold > 0.0 && !self.quality.metrics.iter().any(|m| m == metric) {
+                println!(
+                    "Error: quality.min_{} is set, but {} is not in quality.metrics",
+                    metric, metric
+                );
+                failed = true;
+            }
+        }
+        if self.quality.sample_count > 0 && self.quality.sample_duration == 0 {
+            println!("Error: quality.sample_duration cannot be 0 when sampling");
+            failed = true;
+        }
+
+        if self.verification.duration_tolerance < 0.0 {
+            println!("Error: verification.duration_tolerance cannot be negative");
+            failed = true;
+        }
+
+        if self.watch.poll_interval_secs == 0 {
+            println!("Error: watch.poll_interval_secs cannot be 0");
+            failed = true;
+        }
+
+        for profile in &self.profiles {
+            failed = profile.sanity_check() || failed;
+            match profile.apply(self) {
+                Ok(config) => {
+                    // Values inherited from the global config were already reported
+                    for error in config.target_errors() {
+                        if !target_errors.contains(&error) {
+                            println!("Error: profile {}: {}", profile.name, error);
+                            failed = true;
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    failed = true;
+                }
+            }
+        }
+
         failed
     }
 
+    /// Checks the values of the sections profiles can override.
+    fn target_errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if !SCALERS.contains(&self.video_targets.scaler.as_str()) {
+            errors.push(format!(
+                "video_targets.scaler must be one of: {}",
+                SCALERS.join(", ")
+            ));
+        }
+
+        if self.video_targets.two_pass
+            && self.video_targets.max_bitrate == 0
+            && self.video_targets.target_size_mb == 0
+        {
+            errors.push("two_pass requires max_bitrate or target_size_mb to be set".to_string());
+        }
+
+        match self.video_targets.hdr_mode.as_str() {
+            "preserve" => {}
+            "tonemap" => {}
+            _ => {
+                errors.push("video_targets.hdr_mode must be one of: preserve, tonemap".to_string())
+            }
+        }
+
+        if !TONEMAPS.contains(&self.video_targets.tonemap.as_str()) {
+            errors.push(format!(
+                "video_targets.tonemap must be one of: {}",
+                TONEMAPS.join(", ")
+            ));
+        }
+
+        for kind in &self.filters.order {
+            if !filter_graph::DEFAULT_ORDER.contains(&kind.as_str()) {
+                errors.push(format!(
+                    "unknown filter in filters.order: {} (expected one of: {})",
+                    kind,
+                    filter_graph::DEFAULT_ORDER.join(", ")
+                ));
+            }
+        }
+
+        errors
+    }
+
+    /// Returns the first profile matching `path`, if any.
+    pub fn profile_for(&self, path: &Path) -> Option<&Profile> {
+        let source_directory = Path::new(&self.streamline.source_directory);
+        self.profiles
+            .iter()
+            .find(|profile| profile.matches(path, source_directory))
+    }
+
+    /// Returns the configuration to use for `path`, with any matching profile applied.
+    pub fn for_file(&self, path: &Path) -> Result<Cow<'_, Config>, String> {
+        match self.profile_for(path) {
+            Some(profile) => Ok(Cow::Owned(profile.apply(self)?)),
+            None => Ok(Cow::Borrowed(self)),
+        }
+    }
+
     pub fn get_threads(&self) -> u32 {
         match self.ffmpeg.threads_auto_behavior.as_str() {
             "default" => self.ffmpeg.threads,
@@ -98,6 +279,20 @@ impl Config {
         }
     }
 
+    /// Threads to give each ffmpeg job, so concurrent jobs share the thread budget
+    /// instead of each claiming every core. 0 leaves the choice to ffmpeg.
+    pub fn get_job_threads(&self) -> u32 {
+        let workers = self.ffmpeg.encode_workers.max(1);
+        let mut threads = self.get_threads();
+        if threads == 0 {
+            if workers == 1 {
+                return 0;
+            }
+            threads = available_parallelism().map(|n| n.get() as u32).unwrap_or(1);
+        }
+        (threads / workers).max(1)
+    }
+
     pub fn display(&self) {
         println!();
         println!("   StreamLine Configuration:");
@@ -114,11 +309,27 @@ impl Config {
             self.streamline.file_extensions
         );
         println!("     Output Format: {}", self.streamline.output_format);
+        println!("     Recursive: {}", self.streamline.recursive);
+        println!("     Max Depth: {}", self.streamline.max_depth);
+        println!("     Dry Run: {}", self.streamline.dry_run);
+        if !self.streamline.recycle_directory.is_empty() {
+            println!(
+                "     Recycle Directory: {}",
+                self.streamline.recycle_directory
+            );
+        }
 
         println!("\n   FFmpeg Configuration:");
         println!("     Threads: {}", self.get_threads());
+        println!("     Encode Workers: {}", self.ffmpeg.encode_workers);
+        println!("     Threads Per Job: {}", self.get_job_threads());
         println!("     Video Codec: {:?}", self.video_targets.codec);
         println!("     FFmpeg Preset: {}", self.video_targets.ffmpeg_preset);
+        println!("     HDR Mode: {}", self.video_targets.hdr_mode);
+        println!(
+            "     Dolby Vision: profile 5 {}, profile 7 {}, profile 8 {}",
+            self.dolby_vision.profile_5, self.dolby_vision.profile_7, self.dolby_vision.profile_8
+        );
 
         println!("     Audio Codec: {:?}", self.audio_targets.codec);
         println!("     Language: {:?}", self.audio_targets.language);
@@ -132,10 +343,20 @@ impl Config {
             "     Subtitle Default Language: {}",
             self.subtitles.default_language
         );
+
+        if !self.profiles.is_empty() {
+            println!("\n   Profiles:");
+            for profile in &self.profiles {
+                println!(
+                    "     {}: paths {:?}, directories {:?}",
+                    profile.name, profile.paths, profile.directories
+                );
+            }
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct StreamLine {
     pub source_directory: String,
     pub exclude_directories: Vec<String>,
@@ -151,18 +372,99 @@ pub struct StreamLine {
     pub temp_directory: String,
     pub temporary_suffix: String,
     pub output_directory: String,
+    #[serde(default)]
+    pub remux_mismatches: bool,
+    #[serde(default)]
+    pub probe_cache: String,
+    #[serde(default)]
+    pub journal: String,
+    #[serde(default)]
+    pub stage_inputs: bool,
+    #[serde(default)]
+    pub min_free_space_mb: u64,
+    #[serde(default = "default_low_space_action")]
+    pub low_space_action: String,
+    #[serde(default)]
+    pub recycle_directory: String,
+    #[serde(default)]
+    pub recycle_max_age_days: u32,
+    #[serde(default)]
+    pub recycle_max_size_mb: u64,
+}
+
+/// Whether either path is, or is inside, the other, once both are made absolute.
+fn paths_overlap(a: &str, b: &str) -> bool {
+    let resolve = |path: &str| std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+    let (a, b) = (resolve(a), resolve(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+fn default_low_space_action() -> String {
+    "pause".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+fn default_encode_workers() -> u32 {
+    1
+}
+
+fn default_scaler() -> String {
+    "bicubic".to_string()
+}
+
+fn default_hdr_mode() -> String {
+    "preserve".to_string()
+}
+
+fn default_tonemap() -> String {
+    "hable".to_string()
+}
+
+/// Tonemapping algorithms accepted by ffmpeg's `tonemap` filter.
+pub const TONEMAPS: [&str; 7] = [
+    "none", "clip", "linear", "gamma", "reinhard", "hable", "mobius",
+];
+
+/// What to do with Dolby Vision files:
+/// "skip" - leave the file untouched
+/// "strip" - re-encode the video, keeping only the HDR10 base layer
+/// "remux" - copy the video as is, while still processing the other streams
+pub const DOLBY_VISION_POLICIES: [&str; 3] = ["skip", "strip", "remux"];
+
+/// What to do with a job that would leave less than `min_free_space_mb` free:
+/// "pause" - wait for running jobs to finish, failing the job when none are running
+/// "skip" - fail the job and move on to the next one
+pub const LOW_SPACE_ACTIONS: [&str; 2] = ["pause", "skip"];
+
+/// Metrics the quality check can measure.
+pub const QUALITY_METRICS: [&str; 3] = ["vmaf", "ssim", "psnr"];
+
+/// Scaling algorithms accepted by ffmpeg's `scale` filter.
+pub const SCALERS: [&str; 11] = [
+    "fast_bilinear",
+    "bilinear",
+    "bicubic",
+    "experimental",
+    "neighbor",
+    "area",
+    "bicublin",
+    "gauss",
+    "sinc",
+    "lanczos",
+    "spline",
+];
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct FFmpeg {
     pub ffmpeg_path: String,
     pub ffprobe_path: String,
     pub threads: u32,
     pub threads_auto_behavior: String,
     pub ffprobe_workers: u32,
+    #[serde(default = "default_encode_workers")]
+    pub encode_workers: u32,
     pub log_level: String,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VideoTargets {
     pub codec: Vec<String>,
     pub max_bitrate: u32,
@@ -176,9 +478,21 @@ pub struct VideoTargets {
     pub pix_fmt: String,
     pub tune: String,
     pub x265_params: String,
+    #[serde(default = "default_scaler")]
+    pub scaler: String,
+    #[serde(default)]
+    pub letterbox: bool,
+    #[serde(default)]
+    pub two_pass: bool,
+    #[serde(default)]
+    pub target_size_mb: u32,
+    #[serde(default = "default_hdr_mode")]
+    pub hdr_mode: String,
+    #[serde(default = "default_tonemap")]
+    pub tonemap: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AudioTargets {
     pub codec: Vec<String>,
     pub language: Vec<String>,
@@ -193,18 +507,264 @@ pub struct AudioTargets {
     pub aac_profile: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Subtitles {
     pub codec: Vec<String>,
     pub language: Vec<String>,
     pub default_language: String,
     pub copy_subtitles: bool,
     pub force_subtitles: bool,
+    #[serde(default)]
+    pub drop_images: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Filters {
     pub deinterlace: bool,
     pub deblock: u32,
     pub denoise: u32,
+    #[serde(default)]
+    pub order: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Quality {
+    #[serde(default)]
+    pub metrics: Vec<String>,
+    #[serde(default)]
+    pub min_vmaf: f64,
+    #[serde(default)]
+    pub min_ssim: f64,
+    #[serde(default)]
+    pub min_psnr: f64,
+    #[serde(default)]
+    pub sample_count: u32,
+    #[serde(default = "default_sample_duration")]
+    pub sample_duration: u32,
+}
+
+fn default_sample_duration() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Verification {
+    #[serde(default = "default_verification_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_duration_tolerance")]
+    pub duration_tolerance: f64,
+    #[serde(default)]
+    pub full_decode: bool,
+    #[serde(default)]
+    pub quarantine_directory: String,
+}
+
+impl Default for Verification {
+    fn default() -> Self {
+        Verification {
+            enabled: default_verification_enabled(),
+            duration_tolerance: default_duration_tolerance(),
+            full_decode: false,
+            quarantine_directory: String::new(),
+        }
+    }
+}
+
+fn default_verification_enabled() -> bool {
+    true
+}
+
+fn default_duration_tolerance() -> f64 {
+    2.0
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Watch {
+    #[serde(default = "default_settle_secs")]
+    pub settle_secs: u64,
+    #[serde(default)]
+    pub poll: bool,
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Watch {
+            settle_secs: default_settle_secs(),
+            poll: false,
+            poll_interval_secs: default_poll_interval_secs(),
+        }
+    }
+}
+
+fn default_settle_secs() -> u64 {
+    30
+}
+
+fn default_poll_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DolbyVision {
+    #[serde(default = "default_dolby_vision_skip")]
+    pub profile_5: String,
+    #[serde(default = "default_dolby_vision_skip")]
+    pub profile_7: String,
+    #[serde(default = "default_dolby_vision_remux")]
+    pub profile_8: String,
+}
+
+impl Default for DolbyVision {
+    fn default() -> Self {
+        DolbyVision {
+            profile_5: default_dolby_vision_skip(),
+            profile_7: default_dolby_vision_skip(),
+            profile_8: default_dolby_vision_remux(),
+        }
+    }
+}
+
+impl DolbyVision {
+    /// The policy for Dolby Vision `profile`. Profiles without a policy are skipped.
+    pub fn policy_for(&self, profile: u8) -> &str {
+        match profile {
+            5 => &self.profile_5,
+            7 => &self.profile_7,
+            8 => &self.profile_8,
+            _ => "skip",
+        }
+    }
+}
+
+fn default_dolby_vision_skip() -> String {
+    "skip".to_string()
+}
+
+fn default_dolby_vision_remux() -> String {
+    "remux".to_string()
+}
+
+#[cfg(test)]
+impl Config {
+    /// A fixed configuration for unit tests, independent of the editable sample config.
+    pub(crate) fn for_tests() -> Config {
+        Config {
+            streamline: StreamLine {
+                source_directory: "/library".to_string(),
+                exclude_directories: vec!["extras".to_string()],
+                recursive: true,
+                max_depth: 5,
+                file_extensions: vec!["mkv".to_string(), "mp4".to_string(), "avi".to_string()],
+                dry_run: false,
+                debug: false,
+                output_extension: "mkv".to_string(),
+                output_format: "matroska".to_string(),
+                replace_if_smaller: false,
+                always_replace: false,
+                temp_directory: String::new(),
+                temporary_suffix: "_temp".to_string(),
+                output_directory: String::new(),
+                remux_mismatches: false,
+                probe_cache: String::new(),
+                journal: String::new(),
+                stage_inputs: false,
+                min_free_space_mb: 0,
+                low_space_action: default_low_space_action(),
+                recycle_directory: String::new(),
+                recycle_max_age_days: 0,
+                recycle_max_size_mb: 0,
+            },
+            ffmpeg: FFmpeg {
+                ffmpeg_path: "ffmpeg".to_string(),
+                ffprobe_path: "ffprobe".to_string(),
+                threads: 0,
+                threads_auto_behavior: "default".to_string(),
+                ffprobe_workers: 1,
+                encode_workers: 1,
+                log_level: "error".to_string(),
+            },
+            video_targets: VideoTargets {
+                codec: vec!["hevc".to_string(), "h264".to_string()],
+                max_bitrate: 0,
+                crf: -1,
+                ffmpeg_preset: String::new(),
+                max_width: 0,
+                max_height: 0,
+                max_fps: 0.0,
+                filters: String::new(),
+                force_filter: false,
+                pix_fmt: String::new(),
+                tune: String::new(),
+                x265_params: String::new(),
+                scaler: "bicubic".to_string(),
+                letterbox: false,
+                two_pass: false,
+                target_size_mb: 0,
+                hdr_mode: default_hdr_mode(),
+                tonemap: default_tonemap(),
+            },
+            audio_targets: AudioTargets {
+                codec: vec!["aac".to_string(), "ac3".to_string()],
+                language: vec!["eng".to_string(), "jpn".to_string()],
+                default_language: "eng".to_string(),
+                channel_bitrate: 0,
+                variable_bitrate: 0,
+                sample_rate: Vec::new(),
+                ffmpeg_profile: String::new(),
+                filters: String::new(),
+                force_filter: false,
+                channels: 0,
+                aac_profile: String::new(),
+            },
+            subtitles: Subtitles {
+                codec: vec!["srt".to_string(), "ass".to_string()],
+                language: vec!["eng".to_string(), "jpn".to_string()],
+                default_language: "eng".to_string(),
+                copy_subtitles: false,
+                force_subtitles: false,
+                drop_images: false,
+            },
+            filters: Filters {
+                deinterlace: false,
+                deblock: 0,
+                denoise: 0,
+                order: Vec::new(),
+            },
+            dolby_vision: DolbyVision::default(),
+            quality: Quality::default(),
+            verification: Verification::default(),
+            watch: Watch::default(),
+            profiles: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_values_are_checked() {
+        let mut config = Config::for_tests();
+        assert!(config.target_errors().is_empty());
+
+        config.profiles = vec![toml::from_str(
+            "name = \"bad\"\ndirectories = [\"x\"]\nvideo_targets = { scaler = \"nope\" }\nfilters = { order = [\"blur\"] }",
+        )
+        .unwrap()];
+        let merged = config.profiles[0].apply(&config).unwrap();
+        assert_eq!(merged.target_errors().len(), 2);
+        assert!(merged.target_errors()[0].starts_with("video_targets.scaler"));
+    }
+
+    #[test]
+    fn recycle_directory_must_not_overlap() {
+        assert!(paths_overlap("/library", "/library"));
+        assert!(paths_overlap("/library/.recycle", "/library"));
+        assert!(paths_overlap("/library", "/library/.recycle"));
+        assert!(!paths_overlap("/recycle", "/library"));
+        assert!(!paths_overlap("/library-recycle", "/library"));
+    }
 }
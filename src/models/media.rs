@@ -90,6 +90,136 @@ pub struct Stream {
     pub disposition: Disposition,
     #[serde(default = "Tags::default")]
     pub tags: Tags,
+    #[serde(default)]
+    pub side_data_list: Vec<SideData>,
+}
+
+impl Stream {
+    /// Whether the stream uses an HDR transfer function, PQ (HDR10) or HLG.
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.color_transfer.as_deref(),
+            Some("smpte2084") | Some("arib-std-b67")
+        )
+    }
+
+    fn side_data(&self, side_data_type: &str) -> Option<&SideData> {
+        self.side_data_list
+            .iter()
+            .find(|side_data| side_data.side_data_type == side_data_type)
+    }
+
+    /// The HDR10 mastering display color volume, if the stream carries one.
+    pub fn mastering_display(&self) -> Option<MasteringDisplay> {
+        let side_data = self.side_data("Mastering display metadata")?;
+        let value = |field: &Option<String>| field.as_deref().and_then(utils::parse_rational);
+        Some(MasteringDisplay {
+            red: (value(&side_data.red_x)?, value(&side_data.red_y)?),
+            green: (value(&side_data.green_x)?, value(&side_data.green_y)?),
+            blue: (value(&side_data.blue_x)?, value(&side_data.blue_y)?),
+            white_point: (
+                value(&side_data.white_point_x)?,
+                value(&side_data.white_point_y)?,
+            ),
+            min_luminance: value(&side_data.min_luminance)?,
+            max_luminance: value(&side_data.max_luminance)?,
+        })
+    }
+
+    /// The Dolby Vision configuration record, if the stream carries one.
+    pub fn dolby_vision(&self) -> Option<DolbyVisionConfig> {
+        let side_data = self.side_data("DOVI configuration record")?;
+        Some(DolbyVisionConfig {
+            profile: side_data.dv_profile?,
+            level: side_data.dv_level.unwrap_or(0),
+            enhancement_layer: side_data.el_present_flag == Some(1),
+            compatibility_id: side_data.dv_bl_signal_compatibility_id.unwrap_or(0),
+        })
+    }
+
+    /// The HDR10 content light level (MaxCLL and MaxFALL), if the stream carries one.
+    pub fn content_light_level(&self) -> Option<ContentLightLevel> {
+        let side_data = self.side_data("Content light level metadata")?;
+        Some(ContentLightLevel {
+            max_content: side_data.max_content?,
+            max_average: side_data.max_average?,
+        })
+    }
+
+    /// The width of a pixel relative to its height, from `sample_aspect_ratio`.
+    /// Unknown or unset ratios are treated as square pixels.
+    pub fn pixel_aspect_ratio(&self) -> f64 {
+        let ratio = match &self.sample_aspect_ratio {
+            Some(ratio) => ratio,
+            None => return 1.0,
+        };
+        match ratio.split_once(':') {
+            Some((numerator, denominator)) => {
+                match (numerator.parse::<f64>(), denominator.parse::<f64>()) {
+                    (Ok(numerator), Ok(denominator)) if numerator > 0.0 && denominator > 0.0 => {
+                        numerator / denominator
+                    }
+                    _ => 1.0,
+                }
+            }
+            None => 1.0,
+        }
+    }
+}
+
+/// An entry of ffprobe's `side_data_list`. Only the fields of the side data types
+/// we use are kept.
+#[derive(Deserialize, Debug, Default)]
+pub struct SideData {
+    #[serde(default = "String::new")]
+    pub side_data_type: String,
+    // Mastering display metadata, as rationals
+    pub red_x: Option<String>,
+    pub red_y: Option<String>,
+    pub green_x: Option<String>,
+    pub green_y: Option<String>,
+    pub blue_x: Option<String>,
+    pub blue_y: Option<String>,
+    pub white_point_x: Option<String>,
+    pub white_point_y: Option<String>,
+    pub min_luminance: Option<String>,
+    pub max_luminance: Option<String>,
+    // Content light level metadata
+    pub max_content: Option<u32>,
+    pub max_average: Option<u32>,
+    // DOVI configuration record
+    pub dv_profile: Option<u8>,
+    pub dv_level: Option<u8>,
+    pub el_present_flag: Option<u8>,
+    pub dv_bl_signal_compatibility_id: Option<u8>,
+}
+
+/// Chromaticity coordinates of the mastering display primaries and white point, and
+/// its luminance range in cd/m².
+#[derive(Debug, Clone, PartialEq)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DolbyVisionConfig {
+    pub profile: u8,
+    pub level: u8,
+    /// Whether the stream carries an enhancement layer
+    pub enhancement_layer: bool,
+    /// What the base layer is compatible with: 1 for HDR10, 2 for SDR, 4 for HLG
+    pub compatibility_id: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentLightLevel {
+    pub max_content: u32,
+    pub max_average: u32,
 }
 
 #[derive(Deserialize, Debug, Default)]
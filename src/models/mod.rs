@@ -1,3 +1,4 @@
 pub mod config;
 pub mod file;
 pub mod media;
+pub mod profile;
@@ -0,0 +1,170 @@
+use glob::Pattern;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::models::config::Config;
+
+/// A named set of target overrides, applied to files matching its paths or directories.
+///
+/// Only the keys present in a profile's sections replace the global values, e.g.
+/// ```toml
+/// [[profiles]]
+/// name = "anime"
+/// directories = ["Anime"]
+/// video_targets = { tune = "animation", crf = 20 }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// Glob patterns matched against the full path and the path relative to `source_directory`
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Directory names, matched against any parent directory of the file
+    #[serde(default)]
+    pub directories: Vec<String>,
+    #[serde(default)]
+    pub video_targets: toml::Table,
+    #[serde(default)]
+    pub audio_targets: toml::Table,
+    #[serde(default)]
+    pub subtitles: toml::Table,
+    #[serde(default)]
+    pub filters: toml::Table,
+}
+
+impl Profile {
+    pub fn matches(&self, path: &Path, source_directory: &Path) -> bool {
+        let relative_path = path.strip_prefix(source_directory).unwrap_or(path);
+
+        let path_matches = self
+            .paths
+            .iter()
+            .any(|pattern| match Pattern::new(pattern) {
+                Ok(pattern) => pattern.matches_path(path) || pattern.matches_path(relative_path),
+                Err(_) => false,
+            });
+        if path_matches {
+            return true;
+        }
+
+        match relative_path.parent() {
+            Some(parent) => parent.iter().any(|component| {
+                self.directories
+                    .iter()
+                    .any(|dir| component.to_string_lossy() == dir.as_str())
+            }),
+            None => false,
+        }
+    }
+
+    /// Returns a copy of `config` with this profile's overrides applied.
+    pub fn apply(&self, config: &Config) -> Result<Config, String> {
+        let mut config = config.clone();
+        config.video_targets = self.merge_section(&config.video_targets, &self.video_targets)?;
+        config.audio_targets = self.merge_section(&config.audio_targets, &self.audio_targets)?;
+        config.subtitles = self.merge_section(&config.subtitles, &self.subtitles)?;
+        config.filters = self.merge_section(&config.filters, &self.filters)?;
+        Ok(config)
+    }
+
+    pub fn sanity_check(&self) -> bool {
+        let mut failed = false;
+
+        if self.paths.is_empty() && self.directories.is_empty() {
+            println!(
+                "Error: profile {} must set at least one of paths or directories",
+                self.name
+            );
+            failed = true;
+        }
+
+        for pattern in &self.paths {
+            if let Err(e) = Pattern::new(pattern) {
+                println!(
+                    "Error: invalid path pattern in profile {} ({}): {}",
+                    self.name, pattern, e
+                );
+                failed = true;
+            }
+        }
+
+        failed
+    }
+
+    fn merge_section<T>(&self, base: &T, overrides: &toml::Table) -> Result<T, String>
+    where
+        T: Clone + Serialize + DeserializeOwned,
+    {
+        if overrides.is_empty() {
+            return Ok(base.clone());
+        }
+
+        let mut table = toml::Table::try_from(base).map_err(|e| e.to_string())?;
+        for (key, value) in overrides {
+            if !table.contains_key(key) {
+                return Err(format!("Unknown key in profile {}: {}", self.name, key));
+            }
+            table.insert(key.clone(), value.clone());
+        }
+        table
+            .try_into()
+            .map_err(|e| format!("Invalid value in profile {}: {}", self.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(overrides: &str) -> Profile {
+        toml::from_str(&format!(
+            "name = \"anime\"\npaths = [\"Shows/*.mkv\"]\ndirectories = [\"Anime\"]\n{}",
+            overrides
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_paths_and_directories() {
+        let profile = profile("");
+        let source = Path::new("/library");
+
+        assert!(profile.matches(Path::new("/library/Shows/a.mkv"), source));
+        assert!(profile.matches(Path::new("/library/Anime/Show/a.mkv"), source));
+        assert!(profile.matches(Path::new("/library/TV/Anime/a.mkv"), source));
+        assert!(!profile.matches(Path::new("/library/Shows/a.mp4"), source));
+        // Only parent directories count, not the file name
+        assert!(!profile.matches(Path::new("/library/Movies/Anime"), source));
+        // Nor directories above the source directory
+        assert!(!profile.matches(
+            Path::new("/Anime/library/a.mkv"),
+            Path::new("/Anime/library")
+        ));
+    }
+
+    #[test]
+    fn overrides_only_listed_keys() {
+        let profile = profile("video_targets = { tune = \"animation\", crf = 20 }");
+        let base = Config::for_tests();
+        let config = profile.apply(&base).unwrap();
+
+        assert_eq!(config.video_targets.tune, "animation");
+        assert_eq!(config.video_targets.crf, 20);
+        assert_eq!(config.video_targets.codec, base.video_targets.codec);
+        assert_eq!(config.audio_targets.language, base.audio_targets.language);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_bad_values() {
+        let base = Config::for_tests();
+
+        let unknown = profile("video_targets = { crff = 20 }").apply(&base);
+        assert_eq!(unknown.unwrap_err(), "Unknown key in profile anime: crff");
+
+        let invalid = profile("video_targets = { crf = \"high\" }").apply(&base);
+        assert!(invalid
+            .unwrap_err()
+            .starts_with("Invalid value in profile anime:"));
+    }
+}
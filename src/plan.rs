@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::decision::Reason;
+use crate::stream_plan::StreamPlan;
+
+/// What to do with the source file once an encode completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replacement {
+    /// Replace the source with the output
+    Always,
+    /// Replace the source only if the output is smaller, otherwise discard the output
+    IfSmaller,
+    /// Keep the source and move the output to `output`
+    Never,
+}
+
+/// A single planned ffmpeg run, with everything needed to execute it later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub input: PathBuf,
+    /// Local copy of `input` that ffmpeg reads instead, when inputs are staged
+    #[serde(default)]
+    pub staged_input: Option<PathBuf>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub reasons: Vec<Reason>,
+    pub program: String,
+    pub args: Vec<String>,
+    /// Where ffmpeg writes the encode
+    pub temp_output: PathBuf,
+    /// Where the encode ends up when the source is kept
+    pub output: PathBuf,
+    pub replacement: Replacement,
+    /// Duration of the input in seconds, used for progress reporting
+    #[serde(default)]
+    pub duration_secs: f64,
+    /// What happens to each input stream
+    #[serde(default)]
+    pub streams: StreamPlan,
+    /// Arguments for the first pass of a two-pass encode
+    #[serde(default)]
+    pub first_pass_args: Option<Vec<String>>,
+    /// Prefix of the pass log files written by a two-pass encode
+    #[serde(default)]
+    pub passlog: Option<PathBuf>,
+}
+
+impl Job {
+    /// The file ffmpeg reads: the staged copy of the input if there is one.
+    pub fn encode_input(&self) -> &Path {
+        self.staged_input.as_deref().unwrap_or(&self.input)
+    }
+
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command
+    }
+
+    pub fn first_pass_command(&self) -> Option<Command> {
+        let args = self.first_pass_args.as_ref()?;
+        let mut command = Command::new(&self.program);
+        command.args(args);
+        Some(command)
+    }
+}
+
+/// A reviewable list of jobs, written by `plan` and executed by `apply`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    pub jobs: Vec<Job>,
+}
+
+impl Plan {
+    pub fn load(plan_path: &Path) -> Result<Plan, String> {
+        let contents = fs::read_to_string(plan_path)
+            .map_err(|e| format!("Error reading plan ({}): {}", plan_path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Error parsing plan ({}): {}", plan_path.display(), e))
+    }
+
+    pub fn save(&self, plan_path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(plan_path, contents)
+            .map_err(|e| format!("Error writing plan ({}): {}", plan_path.display(), e))
+    }
+}
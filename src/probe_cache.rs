@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+use crate::utils;
+
+/// On-disk cache of raw ffprobe output, keyed by path and invalidated by size and mtime.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProbeCache {
+    #[serde(skip)]
+    cache_path: PathBuf,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    output: String,
+}
+
+impl CacheEntry {
+    fn new(metadata: &Metadata, output: String) -> Self {
+        let (modified_secs, modified_nanos) = utils::get_modified_time(metadata);
+        CacheEntry {
+            size: metadata.len(),
+            modified_secs,
+            modified_nanos,
+            output,
+        }
+    }
+
+    fn is_fresh(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len()
+            && (self.modified_secs, self.modified_nanos) == utils::get_modified_time(metadata)
+    }
+}
+
+impl ProbeCache {
+    /// Loads the cache at `cache_path`, starting empty if it is missing or unreadable.
+    pub fn load(cache_path: &Path) -> ProbeCache {
+        let mut cache = match fs::read_to_string(cache_path) {
+            Ok(contents) => match serde_json::from_str::<ProbeCache>(&contents) {
+                Ok(cache) => cache,
+                Err(e) => {
+                    println!(
+                        "Error parsing probe cache ({}), starting fresh: {}",
+                        cache_path.display(),
+                        e
+                    );
+                    ProbeCache::default()
+                }
+            },
+            Err(_) => ProbeCache::default(),
+        };
+        cache.cache_path = cache_path.to_path_buf();
+        cache
+    }
+
+    /// Returns the cached ffprobe output for `path` if the file is unchanged.
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<&str> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.is_fresh(metadata))
+            .map(|entry| entry.output.as_str())
+    }
+
+    pub fn insert(&mut self, path: &Path, metadata: &Metadata, output: String) {
+        self.entries
+            .insert(path.to_path_buf(), CacheEntry::new(metadata, output));
+    }
+
+    /// Removes entries for files that no longer exist, returning how many were removed.
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|path, _| path.is_file());
+        before - self.entries.len()
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let contents = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        let temp_path = self.cache_path.with_extension("tmp");
+        fs::write(&temp_path, contents)
+            .map_err(|e| format!("Error writing probe cache ({}): {}", temp_path.display(), e))?;
+        fs::rename(&temp_path, &self.cache_path).map_err(|e| {
+            format!(
+                "Error writing probe cache ({}): {}",
+                self.cache_path.display(),
+                e
+            )
+        })
+    }
+}
@@ -0,0 +1,145 @@
+use indicatif::{HumanDuration, ProgressBar};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// A snapshot of ffmpeg's `-progress` output, emitted once per reporting block.
+#[derive(Debug, Default, Clone)]
+pub struct EncodeProgress {
+    pub out_time_us: u64,
+    pub fps: f64,
+    pub speed: f64,
+    pub finished: bool,
+}
+
+impl EncodeProgress {
+    /// Estimated time remaining for an input of `duration_secs`, based on the current speed.
+    pub fn eta(&self, duration_secs: f64) -> Option<Duration> {
+        if self.speed <= 0.0 {
+            return None;
+        }
+        let remaining = duration_secs - self.out_time_us as f64 / 1_000_000.0;
+        Some(Duration::from_secs_f64(remaining.max(0.0) / self.speed))
+    }
+}
+
+/// Parses the `key=value` lines ffmpeg writes with `-progress`.
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    current: EncodeProgress,
+}
+
+impl ProgressParser {
+    /// Feeds one line of output, returning a snapshot at the end of each block.
+    pub fn parse_line(&mut self, line: &str) -> Option<EncodeProgress> {
+        let (key, value) = line.trim().split_once('=')?;
+        match key {
+            // Despite its name, out_time_ms is also reported in microseconds
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(out_time_us) = value.parse() {
+                    self.current.out_time_us = out_time_us;
+                }
+            }
+            "fps" => self.current.fps = value.parse().unwrap_or(0.0),
+            "speed" => {
+                self.current.speed = value.trim_end_matches('x').trim().parse().unwrap_or(0.0)
+            }
+            "progress" => {
+                self.current.finished = value == "end";
+                return Some(self.current.clone());
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Runs an ffmpeg command that was given `-progress pipe:1`, updating `pb` as it encodes.
+///
+/// `pb` is measured in milliseconds of `duration_secs`.
+pub fn run_with_progress(
+    command: &mut Command,
+    duration_secs: f64,
+    pb: &ProgressBar,
+) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).map(|_| buffer)
+    });
+
+    let mut parser = ProgressParser::default();
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let progress = match parser.parse_line(&line?) {
+            Some(progress) => progress,
+            None => continue,
+        };
+        pb.set_position(progress.out_time_us / 1000);
+        let eta = match progress.eta(duration_secs) {
+            Some(eta) => HumanDuration(eta).to_string(),
+            None => "-".to_string(),
+        };
+        pb.set_message(format!(
+            "{:.1} fps, {:.2}x, ETA {}",
+            progress.fps, progress.speed, eta
+        ));
+    }
+
+    let status = child.wait()?;
+    let stderr = stderr_reader.join().unwrap()?;
+    Ok(Output {
+        status,
+        stdout: Vec::new(),
+        stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_progress_blocks() {
+        let mut parser = ProgressParser::default();
+        for line in [
+            "frame=240",
+            "fps=48.5",
+            "out_time_us=10000000",
+            "speed=2.5x",
+        ] {
+            assert!(parser.parse_line(line).is_none());
+        }
+        let progress = parser.parse_line("progress=continue").unwrap();
+        assert_eq!(progress.out_time_us, 10_000_000);
+        assert_eq!(progress.fps, 48.5);
+        assert_eq!(progress.speed, 2.5);
+        assert!(!progress.finished);
+        assert_eq!(progress.eta(60.0), Some(Duration::from_secs(20)));
+
+        // Values carry over into the next block until they are reported again
+        parser.parse_line("out_time_ms=12000000");
+        let progress = parser.parse_line("progress=end").unwrap();
+        assert_eq!(progress.out_time_us, 12_000_000);
+        assert_eq!(progress.speed, 2.5);
+        assert!(progress.finished);
+    }
+
+    #[test]
+    fn ignores_unusable_lines() {
+        let mut parser = ProgressParser::default();
+        assert!(parser.parse_line("").is_none());
+        assert!(parser.parse_line("not a key value pair").is_none());
+        parser.parse_line("out_time_us=N/A");
+        parser.parse_line("speed=N/A");
+        let progress = parser.parse_line("progress=continue").unwrap();
+        assert_eq!(progress.out_time_us, 0);
+        assert_eq!(progress.eta(60.0), None);
+    }
+}
@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::models::config::Config;
+
+/// Scores of an encode measured against its source. Each score is the mean over all
+/// compared segments.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QualityScores {
+    #[serde(default)]
+    pub vmaf: Option<f64>,
+    #[serde(default)]
+    pub ssim: Option<f64>,
+    #[serde(default)]
+    pub psnr: Option<f64>,
+}
+
+impl QualityScores {
+    /// Reads the scores ffmpeg logs at the end of a `libvmaf`, `ssim` or `psnr` run.
+    fn parse(stderr: &str) -> QualityScores {
+        let mut scores = QualityScores::default();
+        for line in stderr.lines() {
+            if let Some((_, score)) = line.split_once("VMAF score: ") {
+                scores.vmaf = score.trim().parse().ok();
+            } else if let Some((_, rest)) = line.split_once("] SSIM ") {
+                scores.ssim = field_value(rest, "All:");
+            } else if let Some((_, rest)) = line.split_once("] PSNR ") {
+                scores.psnr = field_value(rest, "average:");
+            }
+        }
+        scores
+    }
+
+    /// Rejects scores below the configured minimums.
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        for (metric, score, minimum) in [
+            ("VMAF", self.vmaf, config.quality.min_vmaf),
+            ("SSIM", self.ssim, config.quality.min_ssim),
+            ("PSNR", self.psnr, config.quality.min_psnr),
+        ] {
+            if minimum <= 0.0 {
+                continue;
+            }
+            match score {
+                Some(score) if score >= minimum => {}
+                Some(score) => {
+                    return Err(format!(
+                        "{} {:.3} is below the minimum of {}",
+                        metric, score, minimum
+                    ))
+                }
+                None => return Err(format!("{} could not be measured", metric)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for QualityScores {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut scores = Vec::new();
+        if let Some(vmaf) = self.vmaf {
+            scores.push(format!("VMAF {:.2}", vmaf));
+        }
+        if let Some(ssim) = self.ssim {
+            scores.push(format!("SSIM {:.4}", ssim));
+        }
+        if let Some(psnr) = self.psnr {
+            scores.push(format!("PSNR {:.2}dB", psnr));
+        }
+        write!(f, "{}", scores.join(", "))
+    }
+}
+
+/// The number following `key` in a line of `key:value` pairs.
+fn field_value(line: &str, key: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(key)?;
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+fn mean(scores: &[Option<f64>]) -> Option<f64> {
+    let scores: Vec<f64> = scores.iter().flatten().copied().collect();
+    if scores.is_empty() {
+        return None;
+    }
+    Some(scores.iter().sum::<f64>() / scores.len() as f64)
+}
+
+/// Measures how closely encodes match their sources with ffmpeg's quality filters.
+pub struct QualityChecker<'a> {
+    config: &'a Config,
+}
+
+impl<'a> QualityChecker<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        QualityChecker { config }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.quality.metrics.is_empty()
+    }
+
+    /// Compares the first video stream of `encoded` against `source`, either in full or
+    /// over `sample_count` evenly spaced segments.
+    pub fn measure(
+        &self,
+        source: &Path,
+        encoded: &Path,
+        duration_secs: f64,
+    ) -> Result<QualityScores, String> {
+        let segments = self.segments(duration_secs);
+        let mut measured = Vec::new();
+        for segment in &segments {
+            measured.push(self.measure_segment(source, encoded, *segment)?);
+        }
+
+        Ok(QualityScores {
+            vmaf: mean(&measured.iter().map(|s| s.vmaf).collect::<Vec<_>>()),
+            ssim: mean(&measured.iter().map(|s| s.ssim).collect::<Vec<_>>()),
+            psnr: mean(&measured.iter().map(|s| s.psnr).collect::<Vec<_>>()),
+        })
+    }
+
+    /// Start and length of each segment to compare, or a single `None` for the whole file.
+    fn segments(&self, duration_secs: f64) -> Vec<Option<(f64, f64)>> {
+        let quality = &self.config.quality;
+        let sample_duration = quality.sample_duration as f64;
+        let total = sample_duration * quality.sample_count as f64;
+        if quality.sample_count == 0 || duration_secs <= total {
+            return vec![None];
+        }
+
+        (1..=quality.sample_count)
+            .map(|i| {
+                let center = duration_secs * i as f64 / (quality.sample_count + 1) as f64;
+                Some(((center - sample_duration / 2.0).max(0.0), sample_duration))
+            })
+            .collect()
+    }
+
+    fn measure_segment(
+        &self,
+        source: &Path,
+        encoded: &Path,
+        segment: Option<(f64, f64)>,
+    ) -> Result<QualityScores, String> {
+        let mut command = Command::new(&self.config.ffmpeg.ffmpeg_path);
+        command.arg("-hide_banner").arg("-nostats");
+        command.arg("-v").arg("info");
+        for input in [encoded, source] {
+            if let Some((start, length)) = segment {
+                command
+                    .arg("-ss")
+                    .arg(format!("{:.3}", start))
+                    .arg("-t")
+                    .arg(format!("{:.3}", length));
+            }
+            command.arg("-i").arg(input);
+        }
+        command.arg("-lavfi").arg(self.filter_graph());
+        command.arg("-f").arg("null").arg("-");
+
+        let output = command
+            .output()
+            .map_err(|e| format!("Error running quality check: {}", e))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(format!("Error running quality check: {}", stderr));
+        }
+        Ok(QualityScores::parse(&stderr))
+    }
+
+    /// Scales the source to the size of the encode, aligns their timestamps and feeds
+    /// both into each metric's filter. The encode is the first input.
+    fn filter_graph(&self) -> String {
+        let metrics = &self.config.quality.metrics;
+        let count = metrics.len();
+        let labels = |prefix: &str| {
+            (0..count)
+                .map(|i| format!("[{}{}]", prefix, i))
+                .collect::<String>()
+        };
+
+        let mut graph = vec![
+            format!(
+                "[1:v:0][0:v:0]scale2ref=flags={}[ref][dist]",
+                self.config.video_targets.scaler
+            ),
+            format!(
+                "[dist]settb=AVTB,setpts=PTS-STARTPTS,split={}{}",
+                count,
+                labels("d")
+            ),
+            format!(
+                "[ref]settb=AVTB,setpts=PTS-STARTPTS,split={}{}",
+                count,
+                labels("r")
+            ),
+        ];
+        for (i, metric) in metrics.iter().enumerate() {
+            let filter = match metric.as_str() {
+                "vmaf" => match self.config.get_job_threads() {
+                    0 => "libvmaf".to_string(),
+                    threads => format!("libvmaf=n_threads={}", threads),
+                },
+                other => other.to_string(),
+            };
+            graph.push(format!("[d{}][r{}]{}", i, i, filter));
+        }
+        graph.join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_logged_scores() {
+        let stderr = "\
+[Parsed_libvmaf_0 @ 0x55d0c8] VMAF score: 95.123456
+[Parsed_ssim_0 @ 0x55d0c9] SSIM Y:0.990 (20.0) U:0.995 (23.0) V:0.994 (22.2) All:0.992000 (20.969100)
+[Parsed_psnr_0 @ 0x55d0ca] PSNR y:42.10 u:46.20 v:45.90 average:43.250000 min:38.10 max:50.20
+";
+        let scores = QualityScores::parse(stderr);
+        assert_eq!(scores.vmaf, Some(95.123456));
+        assert_eq!(scores.ssim, Some(0.992));
+        assert_eq!(scores.psnr, Some(43.25));
+    }
+
+    #[test]
+    fn missing_scores_are_none() {
+        let scores = QualityScores::parse("frame=  100 fps=50 q=-0.0 size=N/A\n");
+        assert_eq!(scores.vmaf, None);
+        assert_eq!(scores.ssim, None);
+        assert_eq!(scores.psnr, None);
+    }
+}
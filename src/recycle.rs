@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::models::config::Config;
+use crate::utils;
+
+/// File in the recycle directory listing the originals Streamline moved there. Only
+/// the files it lists are ever purged.
+const MANIFEST_NAME: &str = "streamline-recycle.json";
+
+/// Serializes access to the manifest between workers replacing files at the same time.
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Holds originals replaced by their encodes, so a bad run can be undone until the
+/// retention limits purge them.
+pub struct RecycleBin<'a> {
+    config: &'a Config,
+}
+
+/// Files removed by [`RecycleBin::purge`].
+#[derive(Debug, Default)]
+pub struct PurgeSummary {
+    pub removed: Vec<PathBuf>,
+    pub freed_bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    files: Vec<RecycledFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecycledFile {
+    /// Path relative to the recycle directory
+    path: PathBuf,
+    original: PathBuf,
+    size: u64,
+    /// When the file was recycled, in seconds since the Unix epoch. Retention counts
+    /// from here rather than from the file's own timestamps, which are left untouched.
+    recycled_secs: u64,
+}
+
+impl<'a> RecycleBin<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        RecycleBin { config }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.streamline.recycle_directory.is_empty()
+    }
+
+    fn directory(&self) -> &Path {
+        Path::new(&self.config.streamline.recycle_directory)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.directory().join(MANIFEST_NAME)
+    }
+
+    /// Moves `original` into the recycle directory, at its path relative to the source
+    /// directory, and records it in the manifest. Returns where it was moved to.
+    pub fn recycle(&self, original: &Path) -> Result<PathBuf, String> {
+        let relative = match original.strip_prefix(&self.config.streamline.source_directory) {
+            Ok(relative) => relative.to_path_buf(),
+            // Outside the source directory, such as a plan made with another config
+            Err(_) => original
+                .components()
+                .filter(|component| matches!(component, Component::Normal(_)))
+                .collect(),
+        };
+
+        let _lock = MANIFEST_LOCK.lock().unwrap();
+        let mut manifest = self.load_manifest()?;
+        let destination = utils::unique_path(&self.directory().join(relative));
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                format!(
+                    "Error creating recycle directory ({}): {}",
+                    parent.display(),
+                    e
+                )
+            })?;
+        }
+        let size = fs::metadata(original)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        utils::move_file(original, &destination)
+            .map_err(|e| format!("Error recycling {}: {}", original.display(), e))?;
+
+        let path = destination
+            .strip_prefix(self.directory())
+            .unwrap_or(&destination)
+            .to_path_buf();
+        manifest.files.retain(|file| file.path != path);
+        manifest.files.push(RecycledFile {
+            path,
+            original: original.to_path_buf(),
+            size,
+            recycled_secs: unix_secs(SystemTime::now()),
+        });
+        // An unrecorded file would never be purged, so the original is put back instead
+        if let Err(e) = self.save_manifest(&manifest) {
+            return match utils::move_file(&destination, original) {
+                Ok(()) => Err(e),
+                Err(restore_error) => Err(format!(
+                    "{} (original left at {}: {})",
+                    e,
+                    destination.display(),
+                    restore_error
+                )),
+            };
+        }
+        Ok(destination)
+    }
+
+    /// Removes recycled files past `recycle_max_age_days`, then the oldest ones until
+    /// the recycled files fit in `recycle_max_size_mb`. Everything recycled is removed
+    /// when `all` is set. Files the manifest doesn't list are never touched. In dry run
+    /// mode the files are only listed.
+    pub fn purge(&self, all: bool) -> Result<PurgeSummary, String> {
+        self.purge_at(all, SystemTime::now())
+    }
+
+    fn purge_at(&self, all: bool, now: SystemTime) -> Result<PurgeSummary, String> {
+        let mut summary = PurgeSummary::default();
+        if !self.is_enabled() || !self.manifest_path().exists() {
+            return Ok(summary);
+        }
+
+        let _lock = MANIFEST_LOCK.lock().unwrap();
+        let mut manifest = self.load_manifest()?;
+        // Files restored or removed by hand are forgotten
+        manifest
+            .files
+            .retain(|file| self.directory().join(&file.path).is_file());
+        manifest.files.sort_by_key(|file| file.recycled_secs);
+
+        let streamline = &self.config.streamline;
+        let max_age = Duration::from_secs(streamline.recycle_max_age_days as u64 * 24 * 60 * 60);
+        let max_size = streamline.recycle_max_size_mb * 1_000_000;
+        let mut total_size: u64 = manifest.files.iter().map(|file| file.size).sum();
+        let now = unix_secs(now);
+
+        let mut kept = Vec::new();
+        let mut error = None;
+        for file in manifest.files {
+            let expired = streamline.recycle_max_age_days > 0
+                && now.saturating_sub(file.recycled_secs) > max_age.as_secs();
+            let oversized = max_size > 0 && total_size > max_size;
+            if error.is_some() || !(all || expired || oversized) {
+                kept.push(file);
+                continue;
+            }
+
+            let path = self.directory().join(&file.path);
+            if streamline.dry_run {
+                println!("Would remove recycled file: {}", path.display());
+            } else if let Err(e) = fs::remove_file(&path) {
+                error = Some(format!("Error removing {}: {}", path.display(), e));
+                kept.push(file);
+                continue;
+            } else {
+                remove_empty_parents(self.directory(), &path);
+            }
+            total_size -= file.size;
+            summary.freed_bytes += file.size;
+            summary.removed.push(path);
+        }
+
+        if !streamline.dry_run {
+            manifest.files = kept;
+            self.save_manifest(&manifest)?;
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(summary),
+        }
+    }
+
+    fn load_manifest(&self) -> Result<Manifest, String> {
+        let manifest_path = self.manifest_path();
+        match fs::read_to_string(&manifest_path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+                format!(
+                    "Error parsing recycle manifest ({}): {}",
+                    manifest_path.display(),
+                    e
+                )
+            }),
+            Err(_) => Ok(Manifest::default()),
+        }
+    }
+
+    fn save_manifest(&self, manifest: &Manifest) -> Result<(), String> {
+        let manifest_path = self.manifest_path();
+        let contents = serde_json::to_string_pretty(manifest).map_err(|e| e.to_string())?;
+        let temp_path = manifest_path.with_extension("tmp");
+        fs::create_dir_all(self.directory())
+            .and_then(|_| File::create(&temp_path))
+            .and_then(|mut file| {
+                file.write_all(contents.as_bytes())?;
+                file.sync_all()
+            })
+            .map_err(|e| {
+                format!(
+                    "Error writing recycle manifest ({}): {}",
+                    temp_path.display(),
+                    e
+                )
+            })?;
+        fs::rename(&temp_path, &manifest_path).map_err(|e| {
+            format!(
+                "Error writing recycle manifest ({}): {}",
+                manifest_path.display(),
+                e
+            )
+        })
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Removes the directories between `path` and `directory` that are left empty,
+/// keeping `directory` itself.
+fn remove_empty_parents(directory: &Path, path: &Path) {
+    for parent in path.ancestors().skip(1) {
+        if parent == directory || !parent.starts_with(directory) {
+            break;
+        }
+        // Only succeeds once the directory is empty
+        if fs::remove_dir(parent).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+    const NOW: u64 = 1_000 * DAY;
+
+    fn test_config(name: &str) -> Config {
+        let directory =
+            std::env::temp_dir().join(format!("streamline-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let mut config = Config::for_tests();
+        config.streamline.recycle_directory = directory.to_string_lossy().into_owned();
+        config
+    }
+
+    /// Creates a recycled file listed in the manifest with `size` and its age in days.
+    fn recycled(manifest: &mut Manifest, directory: &Path, path: &str, size: u64, age: u64) {
+        let full_path = directory.join(path);
+        fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+        fs::write(&full_path, "").unwrap();
+        manifest.files.push(RecycledFile {
+            path: PathBuf::from(path),
+            original: Path::new("/library").join(path),
+            size,
+            recycled_secs: NOW - age * DAY,
+        });
+    }
+
+    fn setup(config: &Config) {
+        let bin = RecycleBin::new(config);
+        let mut manifest = Manifest::default();
+        recycled(&mut manifest, bin.directory(), "old.mkv", 100_000, 40);
+        recycled(
+            &mut manifest,
+            bin.directory(),
+            "Movies/mid.mkv",
+            600_000,
+            10,
+        );
+        recycled(&mut manifest, bin.directory(), "new.mkv", 600_000, 1);
+        bin.save_manifest(&manifest).unwrap();
+        // Not Streamline's, so never purged however old it is
+        fs::write(bin.directory().join("foreign.mkv"), "").unwrap();
+    }
+
+    fn purge(config: &Config, all: bool) -> PurgeSummary {
+        RecycleBin::new(config)
+            .purge_at(all, SystemTime::UNIX_EPOCH + Duration::from_secs(NOW))
+            .unwrap()
+    }
+
+    fn names(paths: &[PathBuf]) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn purges_expired_then_oldest_files() {
+        let mut config = test_config("purge-retention");
+        config.streamline.recycle_max_age_days = 30;
+        config.streamline.recycle_max_size_mb = 1;
+        setup(&config);
+        let bin = RecycleBin::new(&config);
+
+        // Expired first, then the oldest until the rest fits in 1 MB
+        let summary = purge(&config, false);
+        assert_eq!(names(&summary.removed), ["old.mkv", "mid.mkv"]);
+        assert_eq!(summary.freed_bytes, 700_000);
+        assert!(!bin.directory().join("Movies").exists());
+        assert!(bin.directory().join("new.mkv").exists());
+        assert!(bin.directory().join("foreign.mkv").exists());
+
+        let manifest = bin.load_manifest().unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].path, Path::new("new.mkv"));
+
+        fs::remove_dir_all(bin.directory()).unwrap();
+    }
+
+    #[test]
+    fn purges_everything_listed_with_all() {
+        let mut config = test_config("purge-all");
+        setup(&config);
+        let directory = PathBuf::from(&config.streamline.recycle_directory);
+        let listed = |config: &Config| RecycleBin::new(config).load_manifest().unwrap().files.len();
+
+        // Without limits nothing expires
+        assert!(purge(&config, false).removed.is_empty());
+
+        config.streamline.dry_run = true;
+        let summary = purge(&config, true);
+        assert_eq!(summary.removed.len(), 3);
+        assert!(directory.join("old.mkv").exists());
+        assert_eq!(listed(&config), 3);
+
+        config.streamline.dry_run = false;
+        assert_eq!(purge(&config, true).removed.len(), 3);
+        assert_eq!(listed(&config), 0);
+        assert!(!directory.join("old.mkv").exists());
+        assert!(directory.join("foreign.mkv").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
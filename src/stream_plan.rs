@@ -0,0 +1,540 @@
+use serde::{Deserialize, Serialize};
+
+use crate::decision::{DecisionEngine, Reason};
+use crate::models::config::Config;
+use crate::models::media::{FFProbeOutput, Stream};
+
+/// What happens to an input stream in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamAction {
+    Copy,
+    Transcode,
+    Drop,
+}
+
+/// The decision for a single input stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedStream {
+    pub input_index: u32,
+    pub codec_type: String,
+    #[serde(default)]
+    pub source_codec: String,
+    #[serde(default)]
+    pub language: String,
+    pub action: StreamAction,
+    /// Index among the output streams of the same type, `None` when dropped
+    pub output_index: Option<u32>,
+    /// Encoder to use when transcoding
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub frame_rate: Option<f64>,
+    /// Filter graph output to map instead of the input stream
+    #[serde(default)]
+    pub map_label: Option<String>,
+    /// Whether the stream is marked default in the input and in the output
+    pub was_default: bool,
+    pub default: bool,
+}
+
+impl PlannedStream {
+    /// The ffmpeg stream specifier of the output stream, such as `a:1`.
+    pub fn output_specifier(&self) -> Option<String> {
+        let type_specifier = match self.codec_type.as_str() {
+            "video" => "v",
+            "audio" => "a",
+            "subtitle" => "s",
+            "attachment" => "t",
+            _ => "d",
+        };
+        self.output_index
+            .map(|index| format!("{}:{}", type_specifier, index))
+    }
+
+    pub fn is_kept(&self) -> bool {
+        self.action != StreamAction::Drop
+    }
+
+    /// The codec the output stream should be probed as, if known.
+    pub fn expected_codec(&self) -> Option<String> {
+        match (&self.action, &self.codec) {
+            (StreamAction::Transcode, Some(encoder)) => Some(codec_for_encoder(encoder)),
+            (StreamAction::Copy, _) if !self.source_codec.is_empty() => {
+                Some(self.source_codec.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The codec name ffprobe reports for streams written by `encoder`.
+pub(crate) fn codec_for_encoder(encoder: &str) -> String {
+    match encoder {
+        "libx265" | "h265" => "hevc",
+        "libx264" => "h264",
+        "libsvtav1" | "libaom-av1" | "librav1e" => "av1",
+        "libvpx" => "vp8",
+        "libvpx-vp9" => "vp9",
+        "libfdk_aac" => "aac",
+        "libopus" => "opus",
+        "libvorbis" => "vorbis",
+        "libmp3lame" => "mp3",
+        "srt" => "subrip",
+        "dca" => "dts",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Which input streams end up in the output, and how, for a single file.
+///
+/// Every ffmpeg argument that refers to a stream is generated from this plan, so
+/// output indexes always line up with the streams that were actually mapped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamPlan {
+    pub streams: Vec<PlannedStream>,
+}
+
+impl StreamPlan {
+    /// Decides what to do with every stream in `info`. Video streams are always
+    /// re-encoded when `video_filtered` is set, as filters cannot be applied to copies.
+    pub fn new(config: &Config, info: &FFProbeOutput, video_filtered: bool) -> StreamPlan {
+        let decision_engine = DecisionEngine::new(config);
+        // Only the video bitrate can bring a file down to its target size
+        let video_filtered = video_filtered || decision_engine.check_file_size(info).is_some();
+        let mut streams: Vec<PlannedStream> = info
+            .streams
+            .iter()
+            .map(|stream| {
+                let mut planned = PlannedStream {
+                    input_index: stream.index,
+                    codec_type: stream.codec_type.clone(),
+                    source_codec: stream.codec_name.clone(),
+                    language: stream.tags.language.clone(),
+                    action: StreamAction::Copy,
+                    output_index: None,
+                    codec: None,
+                    bitrate: None,
+                    sample_rate: None,
+                    frame_rate: None,
+                    map_label: None,
+                    was_default: stream.disposition.default == 1,
+                    default: stream.disposition.default == 1,
+                };
+                match stream.codec_type.as_str() {
+                    // Cover art is stored as a video stream, but is never re-encoded
+                    "video" if stream.disposition.attached_pic == 1 => {}
+                    "video" => Self::plan_video_stream(
+                        config,
+                        &decision_engine,
+                        stream,
+                        &mut planned,
+                        video_filtered,
+                    ),
+                    "audio" => {
+                        Self::plan_audio_stream(config, &decision_engine, stream, &mut planned)
+                    }
+                    "subtitle" => {
+                        Self::plan_subtitle_stream(&decision_engine, stream, &mut planned)
+                    }
+                    "attachment" => {}
+                    // Data streams (timecodes, chapters tracks...) rarely survive a container change
+                    _ => planned.action = StreamAction::Drop,
+                }
+                planned
+            })
+            .collect();
+
+        // Never strip a file of all of its audio just because no track matched the languages
+        if streams
+            .iter()
+            .filter(|stream| stream.codec_type == "audio")
+            .all(|stream| !stream.is_kept())
+        {
+            for stream in streams
+                .iter_mut()
+                .filter(|stream| stream.codec_type == "audio")
+            {
+                stream.action = match stream.codec {
+                    Some(_) => StreamAction::Transcode,
+                    None => StreamAction::Copy,
+                };
+            }
+        }
+
+        Self::assign_output_indexes(&mut streams);
+        Self::assign_default(
+            &mut streams,
+            "audio",
+            &config.audio_targets.default_language,
+            true,
+        );
+        Self::assign_default(
+            &mut streams,
+            "subtitle",
+            &config.subtitles.default_language,
+            false,
+        );
+
+        StreamPlan { streams }
+    }
+
+    fn plan_video_stream(
+        config: &Config,
+        decision_engine: &DecisionEngine,
+        stream: &Stream,
+        planned: &mut PlannedStream,
+        video_filtered: bool,
+    ) {
+        let video_targets = &config.video_targets;
+        let reasons = decision_engine.check_video_stream(stream);
+        if reasons.iter().any(Reason::is_informational) {
+            // Dolby Vision to be remuxed only, which neither filters nor encodes can touch
+            return;
+        }
+        let needs_transcode = video_targets.force_filter
+            || video_filtered
+            || reasons.iter().any(Reason::requires_transcode);
+        if !needs_transcode {
+            return;
+        }
+
+        planned.action = StreamAction::Transcode;
+        planned.codec = Some(
+            video_targets
+                .codec
+                .first()
+                .unwrap_or(&stream.codec_name)
+                .clone(),
+        );
+        if video_targets.max_bitrate != 0 {
+            planned.bitrate = Some(video_targets.max_bitrate);
+        }
+        if video_targets.max_fps != 0.0 && stream.avg_frame_rate > video_targets.max_fps {
+            planned.frame_rate = Some(video_targets.max_fps);
+        }
+    }
+
+    fn plan_audio_stream(
+        config: &Config,
+        decision_engine: &DecisionEngine,
+        stream: &Stream,
+        planned: &mut PlannedStream,
+    ) {
+        let audio_targets = &config.audio_targets;
+        let reasons = decision_engine.check_audio_stream(stream);
+        let needs_transcode = audio_targets.force_filter
+            || !audio_targets.filters.is_empty()
+            || reasons.iter().any(Reason::requires_transcode);
+
+        // Decided up front, so the stream can be restored if every audio stream is dropped
+        if needs_transcode {
+            planned.codec = Some(
+                audio_targets
+                    .codec
+                    .first()
+                    .unwrap_or(&stream.codec_name)
+                    .clone(),
+            );
+            if audio_targets.channel_bitrate != 0 {
+                planned.bitrate =
+                    Some(audio_targets.channel_bitrate * stream.channels.unwrap_or(2));
+            }
+            if !audio_targets.sample_rate.is_empty()
+                && !audio_targets.sample_rate.contains(&stream.sample_rate)
+            {
+                planned.sample_rate = Some(audio_targets.sample_rate[0]);
+            }
+        }
+
+        planned.action = if reasons
+            .iter()
+            .any(|reason| matches!(reason, Reason::AudioLanguage { .. }))
+        {
+            StreamAction::Drop
+        } else if needs_transcode {
+            StreamAction::Transcode
+        } else {
+            StreamAction::Copy
+        };
+    }
+
+    fn plan_subtitle_stream(
+        decision_engine: &DecisionEngine,
+        stream: &Stream,
+        planned: &mut PlannedStream,
+    ) {
+        for reason in decision_engine.check_subtitle_stream(stream) {
+            match reason {
+                Reason::SubtitleLanguage { .. } | Reason::SubtitleCodec { target: None, .. } => {
+                    planned.action = StreamAction::Drop;
+                    return;
+                }
+                Reason::SubtitleCodec {
+                    target: Some(target),
+                    ..
+                } => {
+                    planned.action = StreamAction::Transcode;
+                    planned.codec = Some(target);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn assign_output_indexes(streams: &mut [PlannedStream]) {
+        let mut counts: Vec<(String, u32)> = Vec::new();
+        for stream in streams.iter_mut().filter(|stream| stream.is_kept()) {
+            let position = match counts
+                .iter()
+                .position(|(codec_type, _)| *codec_type == stream.codec_type)
+            {
+                Some(position) => position,
+                None => {
+                    counts.push((stream.codec_type.clone(), 0));
+                    counts.len() - 1
+                }
+            };
+            stream.output_index = Some(counts[position].1);
+            counts[position].1 += 1;
+        }
+    }
+
+    /// Marks a single kept stream of `codec_type` as default: the first one in
+    /// `default_language`, otherwise the one that already was.
+    fn assign_default(
+        streams: &mut [PlannedStream],
+        codec_type: &str,
+        default_language: &str,
+        fallback_to_first: bool,
+    ) {
+        let kept: Vec<&PlannedStream> = streams
+            .iter()
+            .filter(|stream| stream.codec_type == codec_type && stream.is_kept())
+            .collect();
+        let chosen = kept
+            .iter()
+            .find(|stream| !default_language.is_empty() && stream.language == default_language)
+            .or_else(|| kept.iter().find(|stream| stream.was_default))
+            .or_else(|| match fallback_to_first {
+                true => kept.first(),
+                false => None,
+            })
+            .map(|stream| stream.input_index);
+
+        for stream in streams
+            .iter_mut()
+            .filter(|stream| stream.codec_type == codec_type && stream.is_kept())
+        {
+            stream.default = Some(stream.input_index) == chosen;
+        }
+    }
+
+    /// A copy of the plan with only the video streams, as used for a first encoding pass.
+    pub fn video_only(&self) -> StreamPlan {
+        let mut plan = self.clone();
+        for stream in plan
+            .streams
+            .iter_mut()
+            .filter(|stream| stream.codec_type != "video")
+        {
+            stream.action = StreamAction::Drop;
+            stream.output_index = None;
+        }
+        plan
+    }
+
+    pub fn kept_streams(&self) -> impl Iterator<Item = &PlannedStream> {
+        self.streams.iter().filter(|stream| stream.is_kept())
+    }
+
+    /// The kept streams of `codec_type` that are re-encoded.
+    pub fn transcoded_streams<'a>(
+        &'a self,
+        codec_type: &'a str,
+    ) -> impl Iterator<Item = &'a PlannedStream> {
+        self.streams.iter().filter(move |stream| {
+            stream.codec_type == codec_type && stream.action == StreamAction::Transcode
+        })
+    }
+
+    /// The `-map`, codec, bitrate and disposition arguments for the plan.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for stream in self.kept_streams() {
+            args.push("-map".to_string());
+            match &stream.map_label {
+                Some(label) => args.push(label.clone()),
+                None => args.push(format!("0:{}", stream.input_index)),
+            }
+        }
+
+        for stream in self.kept_streams() {
+            let specifier = match stream.output_specifier() {
+                Some(specifier) => specifier,
+                None => continue,
+            };
+            let codec = match (&stream.action, &stream.codec) {
+                (StreamAction::Transcode, Some(codec)) => codec.clone(),
+                _ => "copy".to_string(),
+            };
+            args.push(format!("-c:{}", specifier));
+            args.push(codec);
+
+            if let Some(bitrate) = stream.bitrate {
+                args.push(format!("-b:{}", specifier));
+                args.push(bitrate.to_string());
+            }
+            if let Some(sample_rate) = stream.sample_rate {
+                args.push(format!("-ar:{}", specifier));
+                args.push(sample_rate.to_string());
+            }
+            if let Some(frame_rate) = stream.frame_rate {
+                args.push(format!("-r:{}", specifier));
+                args.push(frame_rate.to_string());
+            }
+            if stream.default != stream.was_default {
+                args.push(format!("-disposition:{}", specifier));
+                args.push(
+                    if stream.default {
+                        "+default"
+                    } else {
+                        "-default"
+                    }
+                    .to_string(),
+                );
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::media::{Disposition, Format, Tags};
+
+    fn stream(index: u32, codec_type: &str, codec: &str, language: &str, default: bool) -> Stream {
+        Stream {
+            index,
+            codec_type: codec_type.to_string(),
+            codec_name: codec.to_string(),
+            disposition: Disposition {
+                default: default as u32,
+                ..Default::default()
+            },
+            tags: Tags {
+                language: language.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn info(streams: Vec<Stream>) -> FFProbeOutput {
+        FFProbeOutput {
+            streams,
+            format: Format::default(),
+        }
+    }
+
+    #[test]
+    fn output_indexes_skip_dropped_streams() {
+        let info = info(vec![
+            stream(0, "video", "h264", "", true),
+            stream(1, "audio", "aac", "fre", true),
+            stream(2, "audio", "aac", "eng", false),
+            stream(3, "subtitle", "subrip", "fre", false),
+            stream(4, "subtitle", "subrip", "eng", false),
+            stream(5, "data", "bin_data", "", false),
+        ]);
+        let plan = StreamPlan::new(&Config::for_tests(), &info, false);
+
+        let indexes: Vec<(u32, Option<u32>)> = plan
+            .streams
+            .iter()
+            .map(|stream| (stream.input_index, stream.output_index))
+            .collect();
+        assert_eq!(
+            indexes,
+            [
+                (0, Some(0)),
+                (1, None),
+                (2, Some(0)),
+                (3, None),
+                (4, Some(0)),
+                (5, None)
+            ]
+        );
+
+        let args = plan.args().join(" ");
+        assert!(args.starts_with("-map 0:0 -map 0:2 -map 0:4 "));
+        assert!(args.contains("-c:v:0 copy -c:a:0 copy -disposition:a:0 +default"));
+        assert!(args.contains("-c:s:0 copy -disposition:s:0 +default"));
+    }
+
+    #[test]
+    fn default_stays_on_kept_stream() {
+        let info = info(vec![
+            stream(0, "video", "h264", "", true),
+            stream(1, "audio", "aac", "jpn", true),
+            stream(2, "audio", "aac", "eng", false),
+        ]);
+        let mut config = Config::for_tests();
+        config.audio_targets.default_language = String::new();
+        let plan = StreamPlan::new(&config, &info, false);
+
+        assert!(plan.streams[1].default);
+        assert!(!plan.streams[2].default);
+        assert!(!plan
+            .args()
+            .iter()
+            .any(|arg| arg.starts_with("-disposition")));
+    }
+
+    #[test]
+    fn audio_kept_when_no_language_matches() {
+        let info = info(vec![
+            stream(0, "video", "h264", "", true),
+            stream(1, "audio", "aac", "fre", false),
+            stream(2, "audio", "aac", "ger", false),
+        ]);
+        let plan = StreamPlan::new(&Config::for_tests(), &info, false);
+
+        let audio: Vec<&PlannedStream> = plan
+            .kept_streams()
+            .filter(|stream| stream.codec_type == "audio")
+            .collect();
+        assert_eq!(audio.len(), 2);
+        assert!(audio[0].default);
+        assert!(plan.args().contains(&"+default".to_string()));
+    }
+
+    #[test]
+    fn subtitles_converted_for_container() {
+        let info = info(vec![
+            stream(0, "video", "h264", "", true),
+            stream(1, "subtitle", "mov_text", "eng", false),
+            stream(2, "subtitle", "hdmv_pgs_subtitle", "eng", false),
+        ]);
+        let plan = StreamPlan::new(&Config::for_tests(), &info, false);
+
+        assert_eq!(plan.streams[1].action, StreamAction::Transcode);
+        assert_eq!(plan.streams[1].codec.as_deref(), Some("srt"));
+        assert_eq!(plan.streams[1].expected_codec().as_deref(), Some("subrip"));
+        // Matroska can hold image subtitles, even though they can't be converted
+        assert_eq!(plan.streams[2].action, StreamAction::Copy);
+        let args = plan.args().join(" ");
+        assert!(args.contains("-c:s:0 srt") && args.contains("-c:s:1 copy"));
+
+        let mut config = Config::for_tests();
+        config.subtitles.drop_images = true;
+        let plan = StreamPlan::new(&config, &info, false);
+        assert_eq!(plan.streams[2].action, StreamAction::Drop);
+    }
+}
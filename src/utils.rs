@@ -1,7 +1,10 @@
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use serde::{Deserialize, Deserializer};
 use std::fmt::Write;
-use std::time::Duration;
+use std::fs::{self, Metadata};
+use std::io::{self, Read, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 pub fn create_spinner(steady_tick: bool) -> ProgressBar {
     let spinner_style = ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}")
@@ -33,6 +36,28 @@ pub fn create_progress_bar(length: u64, steady_tick: bool, tick_interval: u64) -
     progress
 }
 
+/// Progress bar for a single encode, measured in milliseconds of the input's duration.
+pub fn create_encode_progress_bar(duration_ms: u64, name: &str) -> ProgressBar {
+    let progress = ProgressBar::new(duration_ms);
+    if duration_ms == 0 {
+        progress.set_style(
+            ProgressStyle::with_template("   {spinner} {prefix} {wide_msg}")
+                .unwrap()
+                .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
+        );
+    } else {
+        progress.set_style(
+            ProgressStyle::with_template(
+                "   {spinner} [{bar:30.green/blue}] {percent:>3}% {prefix} {wide_msg}",
+            )
+            .unwrap()
+            .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "),
+        );
+    }
+    progress.set_prefix(name.to_string());
+    progress
+}
+
 pub fn set_pb_finish_message(pb: &ProgressBar, message: String) {
     pb.set_style(
         ProgressStyle::default_bar()
@@ -69,10 +94,112 @@ where
     Ok(numerator / denominator)
 }
 
-pub fn get_system_path_separator() -> String {
-    if cfg!(windows) {
-        "\\".to_string()
-    } else {
-        "/".to_string()
+/// Parses a rational such as ffprobe's `34000/50000`, or a plain number.
+pub fn parse_rational(s: &str) -> Option<f64> {
+    match s.split_once('/') {
+        Some((numerator, denominator)) => {
+            let denominator = denominator.trim().parse::<f64>().ok()?;
+            if denominator == 0.0 {
+                return None;
+            }
+            Some(numerator.trim().parse::<f64>().ok()? / denominator)
+        }
+        None => s.trim().parse::<f64>().ok(),
+    }
+}
+
+/// Returns a file's modification time as seconds and nanoseconds since the Unix epoch.
+pub fn get_modified_time(metadata: &Metadata) -> (u64, u32) {
+    match metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    {
+        Some(duration) => (duration.as_secs(), duration.subsec_nanos()),
+        None => (0, 0),
+    }
+}
+
+/// `path`, or the first of `name.1.ext`, `name.2.ext`... that does not exist yet.
+pub fn unique_path(path: &Path) -> PathBuf {
+    let mut unique = path.to_path_buf();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut suffix = 1;
+    while unique.exists() {
+        unique = path.with_file_name(match path.extension() {
+            Some(extension) => format!("{}.{}.{}", stem, suffix, extension.to_string_lossy()),
+            None => format!("{}.{}", stem, suffix),
+        });
+        suffix += 1;
+    }
+    unique
+}
+
+/// Moves a file, replacing `to` if it exists. Across filesystems, where a rename is not
+/// possible, the file is copied next to `to`, synced to disk and renamed over it, so `to`
+/// is never left partially written.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        result => return result,
+    }
+
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let partial = unique_path(&to.with_file_name(format!(".{}.partial", name)));
+    let copied = fs::copy(from, &partial)
+        .and_then(|_| fs::File::open(&partial)?.sync_all())
+        .and_then(|_| fs::rename(&partial, to));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+/// Copies `from` to `to` and syncs the copy to disk, failing if the copy is not as
+/// long as `from` or reads back from disk with a different CRC-32 than the data read
+/// from `from`. The source is only read once, as it may be on a slow network share.
+pub fn copy_verified(from: &Path, to: &Path) -> io::Result<()> {
+    let expected_len = fs::metadata(from)?.len();
+    let mut reader = fs::File::open(from)?;
+    let mut writer = fs::File::create(to)?;
+    let mut buffer = vec![0; 1 << 20];
+    let mut source_hasher = crc32fast::Hasher::new();
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        source_hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+    writer.sync_all()?;
+
+    let copied_len = writer.metadata()?.len();
+    if copied_len != expected_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "copy is {} bytes, but the source is {} bytes",
+                copied_len, expected_len
+            ),
+        ));
+    }
+
+    let mut reader = fs::File::open(to)?;
+    let mut copy_hasher = crc32fast::Hasher::new();
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copy_hasher.update(&buffer[..read]);
+    }
+    if source_hasher.finalize() != copy_hasher.finalize() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "checksum of the copy does not match the source",
+        ));
     }
+    Ok(())
 }
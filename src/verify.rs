@@ -0,0 +1,98 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::ffprobe::Prober;
+use crate::models::config::Config;
+use crate::plan::Job;
+
+/// Checks that an encode is complete and matches its plan before it may replace anything.
+pub struct Verifier<'a> {
+    config: &'a Config,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Verifier { config }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.verification.enabled
+    }
+
+    /// Re-probes the output of `job` and compares its duration and streams with the
+    /// plan, then optionally decodes it in full.
+    pub fn verify(&self, job: &Job) -> Result<(), String> {
+        let info = Prober::new(self.config)
+            .get_file_info(&job.temp_output)
+            .map_err(|e| format!("Output could not be probed: {}", e))?;
+
+        let tolerance = self.config.verification.duration_tolerance;
+        if job.duration_secs > 0.0 && tolerance > 0.0 {
+            let duration: f64 = info.format.duration.parse().unwrap_or(0.0);
+            if (duration - job.duration_secs).abs() > tolerance {
+                return Err(format!(
+                    "Output is {:.2}s long, but the source is {:.2}s",
+                    duration, job.duration_secs
+                ));
+            }
+        }
+
+        for codec_type in ["video", "audio", "subtitle"] {
+            let planned: Vec<_> = job
+                .streams
+                .kept_streams()
+                .filter(|stream| stream.codec_type == codec_type)
+                .collect();
+            let probed = info.get_streams_of_type(codec_type);
+            if planned.len() != probed.len() {
+                return Err(format!(
+                    "Output has {} {} streams, expected {}",
+                    probed.len(),
+                    codec_type,
+                    planned.len()
+                ));
+            }
+
+            for (planned, probed) in planned.iter().zip(probed) {
+                if let Some(expected) = planned.expected_codec() {
+                    if probed.codec_name != expected {
+                        return Err(format!(
+                            "Output {} stream {} is {}, expected {}",
+                            codec_type,
+                            planned.output_index.unwrap_or(0),
+                            probed.codec_name,
+                            expected
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.config.verification.full_decode {
+            self.decode(&job.temp_output)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes every stream of `path`, failing on the first error.
+    fn decode(&self, path: &Path) -> Result<(), String> {
+        let output = Command::new(&self.config.ffmpeg.ffmpeg_path)
+            .arg("-v")
+            .arg("error")
+            .arg("-xerror")
+            .arg("-i")
+            .arg(path)
+            .arg("-f")
+            .arg("null")
+            .arg("-")
+            .output()
+            .map_err(|e| format!("Error decoding output: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Output failed to decode: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
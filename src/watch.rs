@@ -0,0 +1,173 @@
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::decision::DecisionEngine;
+use crate::discovery::Scanner;
+use crate::ffmpeg::Processor;
+use crate::ffprobe::Prober;
+use crate::models::config::Config;
+
+/// How often pending files are checked for having settled.
+const TICK: Duration = Duration::from_secs(1);
+
+/// A file seen changing, waiting for its size to settle.
+struct Pending {
+    size: u64,
+    since: Instant,
+}
+
+/// Watches the source directory and processes new or changed files once they have
+/// finished being written.
+pub struct LibraryWatcher<'a> {
+    config: &'a Config,
+    refresh_probe: bool,
+}
+
+impl<'a> LibraryWatcher<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        LibraryWatcher {
+            config,
+            refresh_probe: false,
+        }
+    }
+
+    /// Ignore cached results when probing files, as `Prober::refresh_cache`.
+    pub fn refresh_probe(mut self, refresh_probe: bool) -> Self {
+        self.refresh_probe = refresh_probe;
+        self
+    }
+
+    /// Runs until the watcher fails. Files are probed, checked and processed in
+    /// batches once their size has not changed for `watch.settle_secs`.
+    pub fn run(&self) -> Result<(), String> {
+        let (sender, receiver) = mpsc::channel();
+        let _watcher = self.start_watcher(sender)?;
+        println!(
+            "👀 Watching {} for new files...",
+            self.config.streamline.source_directory
+        );
+
+        let scanner = Scanner::new(self.config);
+        let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+        loop {
+            self.receive_events(&receiver, &scanner, &mut pending)?;
+
+            let ready = self.settled_files(&mut pending);
+            if !ready.is_empty() {
+                self.process(ready);
+            }
+        }
+    }
+
+    fn start_watcher(
+        &self,
+        sender: mpsc::Sender<notify::Result<Event>>,
+    ) -> Result<Box<dyn Watcher>, String> {
+        let source = Path::new(&self.config.streamline.source_directory);
+        let mode = match self.config.streamline.recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+
+        if !self.config.watch.poll {
+            let native = RecommendedWatcher::new(sender.clone(), notify::Config::default())
+                .and_then(|mut watcher| watcher.watch(source, mode).map(|_| watcher));
+            match native {
+                Ok(watcher) => return Ok(Box::new(watcher)),
+                Err(e) => println!("Filesystem notifications unavailable ({}), polling", e),
+            }
+        }
+
+        // Network mounts rarely deliver notifications, so they are polled instead
+        let config = notify::Config::default()
+            .with_poll_interval(Duration::from_secs(self.config.watch.poll_interval_secs));
+        let mut watcher = PollWatcher::new(sender, config)
+            .map_err(|e| format!("Error starting watcher: {}", e))?;
+        watcher
+            .watch(source, mode)
+            .map_err(|e| format!("Error watching {}: {}", source.display(), e))?;
+        Ok(Box::new(watcher))
+    }
+
+    /// Adds the media files named in the events received within the next tick to
+    /// `pending`, restarting the wait of those already there.
+    fn receive_events(
+        &self,
+        receiver: &Receiver<notify::Result<Event>>,
+        scanner: &Scanner,
+        pending: &mut HashMap<PathBuf, Pending>,
+    ) -> Result<(), String> {
+        let deadline = Instant::now() + TICK;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let event = match receiver.recv_timeout(timeout) {
+                Ok(Ok(event)) => event,
+                Ok(Err(e)) => {
+                    println!("Watch error: {}", e);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("The watcher stopped unexpectedly".to_string())
+                }
+            };
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                continue;
+            }
+
+            for path in event.paths {
+                if !scanner.is_media_file(&path) {
+                    continue;
+                }
+                let size = fs::metadata(&path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+                pending.insert(
+                    path,
+                    Pending {
+                        size,
+                        since: Instant::now(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Removes and returns the pending files whose size has not changed for
+    /// `watch.settle_secs`. Files that disappeared are forgotten.
+    fn settled_files(&self, pending: &mut HashMap<PathBuf, Pending>) -> Vec<PathBuf> {
+        let settle = Duration::from_secs(self.config.watch.settle_secs);
+        let mut ready = Vec::new();
+        pending.retain(|path, file| {
+            let size = match fs::metadata(path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => return false,
+            };
+            if size != file.size {
+                file.size = size;
+                file.since = Instant::now();
+                return true;
+            }
+            if file.since.elapsed() < settle {
+                return true;
+            }
+            ready.push(path.clone());
+            false
+        });
+        ready
+    }
+
+    fn process(&self, files: Vec<PathBuf>) {
+        let files = Prober::new(self.config)
+            .refresh_cache(self.refresh_probe)
+            .bulk_get_file_info(files);
+        let to_process = DecisionEngine::new(self.config).check_files(files);
+        if !to_process.is_empty() {
+            Processor::new(self.config).process_files(to_process);
+        }
+    }
+}